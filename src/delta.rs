@@ -0,0 +1,263 @@
+use crate::diff;
+
+// deltas describe a new version of a file in terms of an older one:
+// runs of lines copied out of the base, and whatever bytes are new
+
+const COPY_TAG: u8 = 0;
+const INSERT_TAG: u8 = 1;
+
+#[derive(Debug, PartialEq)]
+pub enum DeltaOp {
+    Copy { offset: usize, length: usize },
+    Insert(Vec<u8>),
+}
+
+// byte ranges of each line, newlines included
+fn split_lines(bytes: &[u8]) -> Vec<&[u8]> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for (i, b) in bytes.iter().enumerate() {
+        if *b == b'\n' {
+            lines.push(&bytes[start..i + 1]);
+            start = i + 1;
+        }
+    }
+
+    if start < bytes.len() {
+        lines.push(&bytes[start..]);
+    }
+
+    lines
+}
+
+pub fn compute(base: &[u8], target: &[u8]) -> Vec<DeltaOp> {
+    let base_lines = split_lines(base);
+    let target_lines = split_lines(target);
+
+    let mut offsets = Vec::with_capacity(base_lines.len());
+    let mut offset = 0;
+    for line in base_lines.iter() {
+        offsets.push(offset);
+        offset += line.len();
+    }
+
+    // most edits are small, so trimming the shared ends
+    // keeps the LCS table down to just the changed region
+    let mut prefix = 0;
+    while prefix < base_lines.len()
+        && prefix < target_lines.len()
+        && base_lines[prefix] == target_lines[prefix]
+    {
+        prefix += 1;
+    }
+
+    let mut suffix = 0;
+    while suffix < base_lines.len() - prefix
+        && suffix < target_lines.len() - prefix
+        && base_lines[base_lines.len() - 1 - suffix]
+            == target_lines[target_lines.len() - 1 - suffix]
+    {
+        suffix += 1;
+    }
+
+    // (base line, target line) pairs that survive unchanged
+    let mut matches: Vec<(usize, usize)> = (0..prefix).map(|i| (i, i)).collect();
+
+    let base_middle = &base_lines[prefix..base_lines.len() - suffix];
+    let target_middle = &target_lines[prefix..target_lines.len() - suffix];
+    for pair in diff::lcs(base_middle, target_middle) {
+        matches.push((pair.first + prefix, pair.second + prefix));
+    }
+
+    for i in 0..suffix {
+        matches.push((
+            base_lines.len() - suffix + i,
+            target_lines.len() - suffix + i,
+        ));
+    }
+
+    let mut ops = Vec::new();
+    let mut matches = matches.iter().peekable();
+    for (t, line) in target_lines.iter().enumerate() {
+        match matches.next_if(|m| m.1 == t) {
+            Some(&(b, _)) => match ops.last_mut() {
+                Some(DeltaOp::Copy { offset, length }) if *offset + *length == offsets[b] => {
+                    *length += line.len();
+                }
+                _ => ops.push(DeltaOp::Copy {
+                    offset: offsets[b],
+                    length: line.len(),
+                }),
+            },
+            None => match ops.last_mut() {
+                Some(DeltaOp::Insert(bytes)) => bytes.extend_from_slice(line),
+                _ => ops.push(DeltaOp::Insert(line.to_vec())),
+            },
+        }
+    }
+
+    ops
+}
+
+// None if the ops reach outside of the base
+pub fn apply(base: &[u8], ops: &[DeltaOp]) -> Option<Vec<u8>> {
+    let mut result = Vec::new();
+    for op in ops.iter() {
+        match op {
+            DeltaOp::Copy { offset, length } => {
+                result.extend_from_slice(base.get(*offset..offset.checked_add(*length)?)?)
+            }
+            DeltaOp::Insert(bytes) => result.extend_from_slice(bytes),
+        }
+    }
+
+    Some(result)
+}
+
+pub fn to_bytes(ops: &[DeltaOp]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for op in ops.iter() {
+        match op {
+            DeltaOp::Copy { offset, length } => {
+                bytes.push(COPY_TAG);
+                bytes.extend_from_slice(&(*offset as u64).to_be_bytes());
+                bytes.extend_from_slice(&(*length as u64).to_be_bytes());
+            }
+            DeltaOp::Insert(data) => {
+                bytes.push(INSERT_TAG);
+                bytes.extend_from_slice(&(data.len() as u64).to_be_bytes());
+                bytes.extend_from_slice(data);
+            }
+        }
+    }
+
+    bytes
+}
+
+pub fn from_bytes(bytes: &[u8]) -> Option<Vec<DeltaOp>> {
+    fn read_u64(bytes: &[u8], cursor: &mut usize) -> Option<usize> {
+        let value = u64::from_be_bytes(bytes.get(*cursor..*cursor + 8)?.try_into().ok()?);
+        *cursor += 8;
+
+        usize::try_from(value).ok()
+    }
+
+    let mut ops = Vec::new();
+    let mut cursor = 0;
+    while cursor < bytes.len() {
+        let tag = bytes[cursor];
+        cursor += 1;
+
+        match tag {
+            COPY_TAG => {
+                let offset = read_u64(bytes, &mut cursor)?;
+                let length = read_u64(bytes, &mut cursor)?;
+                ops.push(DeltaOp::Copy { offset, length });
+            }
+            INSERT_TAG => {
+                let length = read_u64(bytes, &mut cursor)?;
+                let data = bytes.get(cursor..cursor.checked_add(length)?)?;
+                cursor += length;
+                ops.push(DeltaOp::Insert(data.to_vec()));
+            }
+            _ => return None,
+        }
+    }
+
+    Some(ops)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // compute -> to_bytes -> from_bytes -> apply has to give back the target
+    fn round_trip(base: &[u8], target: &[u8]) -> Vec<DeltaOp> {
+        let ops = compute(base, target);
+        let decoded = from_bytes(&to_bytes(&ops)).unwrap();
+        assert_eq!(decoded, ops);
+        assert_eq!(apply(base, &decoded).unwrap(), target);
+
+        ops
+    }
+
+    fn numbered(count: usize) -> Vec<u8> {
+        (0..count)
+            .map(|k| format!("line {}\n", k))
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    #[test]
+    fn round_trips() {
+        let base = numbered(20);
+
+        let mut edited = base.clone();
+        edited.splice(7..7, b"inserted\n".iter().copied());
+        round_trip(&base, &edited);
+
+        round_trip(&base, &base[..50]);
+        round_trip(&base, &[&base[..], b"no newline"].concat());
+        round_trip(&base, b"nothing in common\n");
+        round_trip(b"", &base);
+        round_trip(b"\0\x01\xff\n\xfe", b"\0\x01\xff\n\xfd");
+
+        assert!(round_trip(&base, b"").is_empty());
+    }
+
+    #[test]
+    fn unchanged_lines_are_copied_in_one_run() {
+        let base = numbered(100);
+        assert_eq!(
+            round_trip(&base, &base),
+            [DeltaOp::Copy {
+                offset: 0,
+                length: base.len()
+            }]
+        );
+
+        let target = String::from_utf8(base.clone())
+            .unwrap()
+            .replace("line 50\n", "changed\n")
+            .into_bytes();
+        let ops = round_trip(&base, &target);
+        assert_eq!(ops.len(), 3);
+        assert_eq!(ops[1], DeltaOp::Insert(b"changed\n".to_vec()));
+    }
+
+    #[test]
+    fn malformed_deltas_are_rejected() {
+        let bytes = to_bytes(&[
+            DeltaOp::Copy {
+                offset: 0,
+                length: 4,
+            },
+            DeltaOp::Insert(b"new".to_vec()),
+        ]);
+
+        // cut off anywhere inside an op, rather than between the two
+        let copy_length = 1 + 8 + 8;
+        for length in 1..bytes.len() {
+            if length != copy_length {
+                assert!(from_bytes(&bytes[..length]).is_none(), "{}", length);
+            }
+        }
+
+        assert!(from_bytes(&[7]).is_none());
+
+        // an insert longer than what's left, or too long to add up
+        let mut bytes = vec![INSERT_TAG];
+        bytes.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert!(from_bytes(&bytes).is_none());
+    }
+
+    #[test]
+    fn copies_outside_the_base_are_rejected() {
+        let copy = |offset, length| [DeltaOp::Copy { offset, length }];
+
+        assert_eq!(apply(b"base", &copy(0, 4)).unwrap(), b"base");
+        assert!(apply(b"base", &copy(2, 3)).is_none());
+        assert!(apply(b"base", &copy(5, 0)).is_none());
+        assert!(apply(b"base", &copy(1, usize::MAX)).is_none());
+    }
+}
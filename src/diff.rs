@@ -1,6 +1,105 @@
-use std::collections::BTreeSet;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::ops::Range;
+
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::display::{green_highlight_string, green_string, red_highlight_string, red_string};
+
+// diffs are worked out a line at a time with myers' algorithm, and then
+// word by word or character by character within each run of changed lines
+//
+// a "character" here is a grapheme cluster-- what reads as a single character,
+// so accents, emoji sequences and the like are never split apart--
+// and every column and offset counts them rather than bytes or chars
+//
+// myers finds the fewest insertions and deletions that turn one sequence into
+// the other in O((N + M) * D) time, and the divide and conquer refinement
+// (finding the middle of the edit path and recursing on either side of it)
+// keeps the space linear
+
+// the search gives up on an exact answer past this many edits (or the square
+// root of the input size, if that's larger) and settles for a good-enough split,
+// which keeps wildly different inputs from taking quadratic time
+const MIN_COST_LIMIT: usize = 256;
+
+// changed runs bigger than this (in words or graphemes, both sides together)
+// are shown as whole lines rather than diffed any finer
+const TOKEN_DIFF_LIMIT: usize = 20_000;
+
+// how finely changed lines are broken down when comparing them
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Granularity {
+    // whole lines only
+    Line,
+    // words, runs of whitespace and punctuation, as split by unicode word boundaries
+    Word,
+    // grapheme clusters
+    Char,
+}
+
+#[derive(Clone, Copy)]
+pub struct Options {
+    pub granularity: Granularity,
+    // whitespace never counts when comparing lines
+    pub ignore_all_space: bool,
+    // changes in the amount of whitespace don't count, nor does whitespace at the end of a line
+    pub ignore_space_change: bool,
+    // changes that only add or remove blank lines don't count
+    pub ignore_blank_lines: bool,
+    // a line ending in \r\n is the same as one ending in \n
+    pub ignore_cr_at_eol: bool,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            granularity: Granularity::Char,
+            ignore_all_space: false,
+            ignore_space_change: false,
+            ignore_blank_lines: false,
+            ignore_cr_at_eol: false,
+        }
+    }
+}
+
+impl Options {
+    // whether any of the ignore options are turned on
+    pub fn ignores_anything(&self) -> bool {
+        self.ignore_all_space
+            || self.ignore_space_change
+            || self.ignore_blank_lines
+            || self.ignore_cr_at_eol
+    }
+
+    // what a line is compared by, once any whitespace that doesn't count is taken out
+    fn line_key<'a>(&self, line: &'a str) -> Cow<'a, str> {
+        if self.ignore_all_space {
+            Cow::Owned(line.chars().filter(|c| !c.is_whitespace()).collect())
+        } else if self.ignore_space_change {
+            let mut key = String::with_capacity(line.len());
+            for (k, word) in line.split_whitespace().enumerate() {
+                if k > 0 {
+                    key.push(' ');
+                }
+
+                key.push_str(word);
+            }
+
+            // leading whitespace still counts for something, just not how much
+            if line.starts_with(|c: char| c.is_whitespace()) && !key.is_empty() {
+                key.insert(0, ' ');
+            }
 
-use crate::display::{green, red};
+            Cow::Owned(key)
+        } else if self.ignore_cr_at_eol && line.ends_with("\r\n") {
+            Cow::Owned(line[..line.len() - 2].to_string() + "\n")
+        } else {
+            Cow::Borrowed(line)
+        }
+    }
+}
 
 pub struct Pair<T, U> {
     pub first: T,
@@ -26,10 +125,10 @@ where
     }
 }
 
-struct Index {
-    line: usize,
-    column: usize,
-    flat: usize,
+pub struct Index {
+    pub line: usize,
+    pub column: usize,
+    pub flat: usize,
 }
 
 impl Clone for Index {
@@ -42,91 +141,6 @@ impl Clone for Index {
     }
 }
 
-struct IndexedString {
-    pub content: String,
-    pub indices: Vec<Index>,
-}
-
-impl IndexedString {
-    fn new(content: String) -> IndexedString {
-        let mut indices = Vec::new();
-        let mut line = 0;
-        let mut column = 0;
-        let mut flat = 0;
-        for c in content.chars() {
-            indices.push(Index { line, column, flat });
-
-            flat += 1;
-            if c == '\n' {
-                line += 1;
-                column = 0;
-            } else {
-                column += 1;
-            }
-        }
-
-        IndexedString { content, indices }
-    }
-
-    fn len(&self) -> usize {
-        self.content.len()
-    }
-
-    fn get_line(&self, line_number: usize) -> String {
-        match self
-            .indices
-            .binary_search_by(|ind| ind.line.cmp(&line_number))
-        {
-            Ok(index) => {
-                let mut line = String::new();
-                let mut i = index;
-
-                while i != usize::MAX && self.indices[i].line == line_number {
-                    i = i.wrapping_sub(1);
-                }
-
-                i = i.wrapping_add(1);
-                while i < self.indices.len() && self.indices[i].line == line_number {
-                    line.push(self.content.chars().nth(i).unwrap());
-                    i += 1;
-                }
-
-                line
-            }
-            Err(_index) => {
-                eprintln!("line {} not found", line_number);
-                String::new()
-            }
-        }
-    }
-}
-
-pub struct LCSChar {
-    pub value: char,
-    pub source_index: Index,
-    pub changed_index: Index,
-}
-
-impl LCSChar {
-    fn new(v: char, s: Index, c: Index) -> LCSChar {
-        LCSChar {
-            value: v,
-            source_index: s,
-            changed_index: c,
-        }
-    }
-}
-
-impl Clone for LCSChar {
-    fn clone(&self) -> LCSChar {
-        LCSChar {
-            value: self.value,
-            source_index: self.source_index.clone(),
-            changed_index: self.changed_index.clone(),
-        }
-    }
-}
-
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum DiffCharType {
     Addition,
@@ -143,7 +157,8 @@ impl Clone for DiffCharType {
 }
 
 pub struct DiffChar {
-    pub value: char,
+    // a whole grapheme cluster, which can be several chars
+    pub value: String,
     pub index: Index,
     pub char_type: DiffCharType,
 }
@@ -151,348 +166,1260 @@ pub struct DiffChar {
 impl Clone for DiffChar {
     fn clone(&self) -> DiffChar {
         DiffChar {
-            value: self.value,
+            value: self.value.clone(),
             index: self.index.clone(),
             char_type: self.char_type.clone(),
         }
     }
 }
 
+// one line of a line-oriented edit script, by line number on its side
+// (unchanged lines by their number in the source)
+enum LineOp {
+    Context(usize),
+    Removed(usize),
+    Added(usize),
+}
+
+// one word or character of the edit script for a run of changed lines
+enum TokenOp<'a> {
+    Common(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+// a run of changed lines: the source lines removed, and the changed lines that replace them
+struct Gap {
+    source: Range<usize>,
+    changed: Range<usize>,
+    // the ops the gap is made of
+    ops: Range<usize>,
+    // a change the options say doesn't count
+    ignored: bool,
+}
+
+// byte offsets of the start of each line, plus one for the end of the text
+fn line_starts(text: &str) -> Vec<usize> {
+    let mut starts = vec![0];
+    starts.extend(text.match_indices('\n').map(|(i, _)| i + 1));
+    if *starts.last().unwrap() != text.len() {
+        starts.push(text.len());
+    }
+
+    starts
+}
+
+// one of the display coloring functions
+type Paint = fn(&String) -> String;
+
+fn tokenize(text: &str, granularity: Granularity) -> Vec<&str> {
+    match granularity {
+        Granularity::Line => text.split_inclusive('\n').collect(),
+        Granularity::Word => text.split_word_bounds().collect(),
+        Granularity::Char => text.graphemes(true).collect(),
+    }
+}
+
 pub struct Diff {
-    pub source: IndexedString,
-    pub changed: IndexedString,
-    pub lcs: Vec<LCSChar>,
+    // names for each side in unified diff headers
+    pub source_name: String,
+    pub changed_name: String,
+    pub source: String,
+    pub changed: String,
+    source_starts: Vec<usize>,
+    changed_starts: Vec<usize>,
+    ops: Vec<LineOp>,
+    // one for each op, set for changes the options say to ignore
+    ignored: Vec<bool>,
+    granularity: Granularity,
     pub diff: Vec<DiffChar>,
 }
 
 impl Diff {
-    pub fn new(source: IndexedString, changed: IndexedString, lcs: Vec<LCSChar>) -> Diff {
-        Diff {
+    pub fn new(source: String, changed: String, options: &Options) -> Diff {
+        let source_starts = line_starts(&source);
+        let changed_starts = line_starts(&changed);
+
+        let mut diff = Diff {
+            source_name: String::from("a"),
+            changed_name: String::from("b"),
             source,
             changed,
-            lcs,
+            source_starts,
+            changed_starts,
+            ops: Vec::new(),
+            ignored: Vec::new(),
+            granularity: options.granularity,
             diff: Vec::new(),
+        };
+
+        // between two matched lines, everything left over is
+        // a block of removals followed by a block of additions
+        let source_lines = diff.source_lines();
+        let changed_lines = diff.changed_lines();
+        let mut ops = Vec::new();
+        let mut i = 0;
+        let mut j = 0;
+        let matches = lcs(
+            &source_lines
+                .iter()
+                .map(|line| options.line_key(line))
+                .collect::<Vec<Cow<str>>>(),
+            &changed_lines
+                .iter()
+                .map(|line| options.line_key(line))
+                .collect::<Vec<Cow<str>>>(),
+        );
+        for pair in matches.iter().chain(std::iter::once(&Pair::new(
+            source_lines.len(),
+            changed_lines.len(),
+        ))) {
+            ops.extend((i..pair.first).map(LineOp::Removed));
+            ops.extend((j..pair.second).map(LineOp::Added));
+            if pair.first < source_lines.len() {
+                ops.push(LineOp::Context(pair.first));
+            }
+
+            i = pair.first + 1;
+            j = pair.second + 1;
+        }
+
+        diff.ignored = vec![false; ops.len()];
+        diff.ops = ops;
+
+        if options.ignore_blank_lines {
+            let source_lines = diff.source_lines();
+            let changed_lines = diff.changed_lines();
+            let is_blank = |line: &&str| line.trim().is_empty();
+
+            let mut ignored = diff.ignored.clone();
+            for gap in diff.gaps() {
+                if source_lines[gap.source.clone()].iter().all(is_blank)
+                    && changed_lines[gap.changed.clone()].iter().all(is_blank)
+                {
+                    ignored[gap.ops].fill(true);
+                }
+            }
+
+            diff.ignored = ignored;
         }
+
+        diff
+    }
+
+    // whether there's nothing left once ignored changes are taken out
+    pub fn is_unchanged(&self) -> bool {
+        self.ops
+            .iter()
+            .zip(self.ignored.iter())
+            .all(|(op, ignored)| matches!(op, LineOp::Context(_)) || *ignored)
     }
 
+    // fills in `diff` with every character that was added or removed
     pub fn build(&mut self) {
         let mut diff: Vec<DiffChar> = Vec::new();
 
-        let mut source_idx = 0;
-        let mut changed_idx = 0;
-        let mut diff_idx = 0;
+        // character offsets are counted up incrementally, since gaps come in order
+        let mut source_position = (0, 0);
+        let mut changed_position = (0, 0);
+        let flat_offset = |text: &str, position: &mut (usize, usize), byte: usize| {
+            position.1 += text[position.0..byte].graphemes(true).count();
+            position.0 = byte;
 
-        let is_bounded = |s, c| s < self.source.len() && c < self.changed.len();
+            position.1
+        };
 
-        while is_bounded(source_idx, changed_idx) {
-            source_idx = self.parse_source_removal(source_idx, diff_idx, &mut |c, i| {
-                diff.push(DiffChar {
-                    value: c,
-                    index: i,
-                    char_type: DiffCharType::Deletion,
-                });
-            });
-            changed_idx = self.parse_changed_addition(changed_idx, diff_idx, &mut |c, i| {
-                diff.push(DiffChar {
-                    value: c,
-                    index: i,
-                    char_type: DiffCharType::Addition,
-                });
-            });
-            let (new_source_idx, new_changed_idx, new_diff_idx) =
-                self.parse_common_subsequence(source_idx, changed_idx, diff_idx, &mut |_c, _i| {});
+        for gap in self.gaps().into_iter().filter(|gap| !gap.ignored) {
+            let source_byte = self.source_starts[gap.source.start];
+            let changed_byte = self.changed_starts[gap.changed.start];
+            let mut source_index = Index {
+                line: gap.source.start,
+                column: 0,
+                flat: flat_offset(&self.source, &mut source_position, source_byte),
+            };
+            let mut changed_index = Index {
+                line: gap.changed.start,
+                column: 0,
+                flat: flat_offset(&self.changed, &mut changed_position, changed_byte),
+            };
+
+            // "\r\n" is a single grapheme
+            let advance = |index: &mut Index, c: &str| {
+                index.flat += 1;
+                if c.ends_with('\n') {
+                    index.line += 1;
+                    index.column = 0;
+                } else {
+                    index.column += 1;
+                }
+            };
+
+            for op in self.token_script(&gap) {
+                let (token, char_type) = match op {
+                    TokenOp::Common(token) => {
+                        for c in token.graphemes(true) {
+                            advance(&mut source_index, c);
+                            advance(&mut changed_index, c);
+                        }
+
+                        continue;
+                    }
+                    TokenOp::Removed(token) => (token, DiffCharType::Deletion),
+                    TokenOp::Added(token) => (token, DiffCharType::Addition),
+                };
+
+                let index = match char_type {
+                    DiffCharType::Deletion => &mut source_index,
+                    DiffCharType::Addition => &mut changed_index,
+                };
 
-            source_idx = new_source_idx;
-            changed_idx = new_changed_idx;
-            diff_idx = new_diff_idx;
+                for c in token.graphemes(true) {
+                    diff.push(DiffChar {
+                        value: c.to_string(),
+                        index: index.clone(),
+                        char_type: char_type.clone(),
+                    });
+                    advance(index, c);
+                }
+            }
         }
 
         self.diff = diff;
     }
 
-    pub fn to_string(&self) -> String {
-        let mut source_idx = 0;
-        let mut changed_idx = 0;
-        let mut diff_idx = 0;
+    fn source_lines(&self) -> Vec<&str> {
+        self.source_starts
+            .windows(2)
+            .map(|w| &self.source[w[0]..w[1]])
+            .collect()
+    }
 
-        let is_bounded = |s, c| s < self.source.len() && c < self.changed.len();
+    fn changed_lines(&self) -> Vec<&str> {
+        self.changed_starts
+            .windows(2)
+            .map(|w| &self.changed[w[0]..w[1]])
+            .collect()
+    }
 
-        let mut diff_string = String::new();
-        while is_bounded(source_idx, changed_idx) {
-            source_idx = self.parse_source_removal(source_idx, diff_idx, &mut |c, _i| {
-                diff_string.push_str(&red(c))
-            });
-            changed_idx = self.parse_changed_addition(changed_idx, diff_idx, &mut |c, _i| {
-                diff_string.push_str(&green(c))
-            });
-            let (new_source_idx, new_changed_idx, new_diff_idx) =
-                self.parse_common_subsequence(source_idx, changed_idx, diff_idx, &mut |c, _i| {
-                    diff_string.push(c)
+    fn gaps(&self) -> Vec<Gap> {
+        let mut gaps: Vec<Gap> = Vec::new();
+        let mut in_gap = false;
+        let mut source_seen = 0;
+        let mut changed_seen = 0;
+        for (k, op) in self.ops.iter().enumerate() {
+            if let LineOp::Context(_) = op {
+                in_gap = false;
+                source_seen += 1;
+                changed_seen += 1;
+                continue;
+            }
+
+            // an empty side starts wherever the other side's lines would go
+            if !in_gap {
+                gaps.push(Gap {
+                    source: source_seen..source_seen,
+                    changed: changed_seen..changed_seen,
+                    ops: k..k,
+                    ignored: self.ignored.get(k).copied().unwrap_or(false),
                 });
+                in_gap = true;
+            }
 
-            source_idx = new_source_idx;
-            changed_idx = new_changed_idx;
-            diff_idx = new_diff_idx;
+            let gap = gaps.last_mut().unwrap();
+            gap.ops.end = k + 1;
+            if let LineOp::Removed(_) = op {
+                source_seen += 1;
+                gap.source.end = source_seen;
+            } else {
+                changed_seen += 1;
+                gap.changed.end = changed_seen;
+            }
         }
 
-        diff_string
+        gaps
     }
 
-    pub fn to_pretty_string(&self) -> String {
-        let mut source_idx = 0;
-        let mut changed_idx = 0;
-        let mut diff_idx = 0;
-
-        let is_bounded = |s, c| s < self.source.len() && c < self.changed.len();
-
-        let mut added_lines = BTreeSet::new();
-        let mut removed_lines = BTreeSet::new();
-        let mut remained_lines = BTreeSet::new();
-
-        while is_bounded(source_idx, changed_idx) {
-            source_idx = self.parse_source_removal(source_idx, diff_idx, &mut |_c, i| {
-                removed_lines.insert(i.line);
-            });
-            changed_idx = self.parse_changed_addition(changed_idx, diff_idx, &mut |_c, i| {
-                added_lines.insert(i.line);
-            });
-            let (new_source_idx, new_changed_idx, new_diff_idx) =
-                self.parse_common_subsequence(source_idx, changed_idx, diff_idx, &mut |_c, i| {
-                    remained_lines.insert(i.line);
-                });
+    // the edit script turning a gap's removed lines into its added ones,
+    // broken down as finely as the granularity asks for
+    fn token_script(&self, gap: &Gap) -> Vec<TokenOp<'_>> {
+        let removed =
+            &self.source[self.source_starts[gap.source.start]..self.source_starts[gap.source.end]];
+        let added = &self.changed
+            [self.changed_starts[gap.changed.start]..self.changed_starts[gap.changed.end]];
+
+        let removed = tokenize(removed, self.granularity);
+        let added = tokenize(added, self.granularity);
+
+        // lines in a gap never match each other, so there's nothing to compare
+        let matches = if self.granularity == Granularity::Line
+            || removed.is_empty()
+            || added.is_empty()
+            || removed.len() + added.len() > TOKEN_DIFF_LIMIT
+        {
+            Vec::new()
+        } else {
+            lcs(&removed, &added)
+        };
 
-            source_idx = new_source_idx;
-            changed_idx = new_changed_idx;
-            diff_idx = new_diff_idx;
+        let mut script = Vec::new();
+        let mut i = 0;
+        let mut j = 0;
+        for pair in matches.iter() {
+            script.extend(removed[i..pair.first].iter().copied().map(TokenOp::Removed));
+            script.extend(added[j..pair.second].iter().copied().map(TokenOp::Added));
+            script.push(TokenOp::Common(removed[pair.first]));
+            i = pair.first + 1;
+            j = pair.second + 1;
         }
 
-        let line_count = std::cmp::max(
-            self.source.indices.last().unwrap().line,
-            self.changed.indices.last().unwrap().line,
-        );
+        script.extend(removed[i..].iter().copied().map(TokenOp::Removed));
+        script.extend(added[j..].iter().copied().map(TokenOp::Added));
 
-        let added_lines = added_lines.iter().collect::<Vec<&usize>>();
-        let removed_lines = removed_lines.iter().collect::<Vec<&usize>>();
-        let remained_lines = remained_lines.iter().collect::<Vec<&usize>>();
+        script
+    }
 
-        let mut added_index = 0;
-        let mut removed_index = 0;
-        let mut remained_index = 0;
+    // the full text with removed characters in red and added ones in green
+    pub fn to_string(&self) -> String {
+        let source_lines = self.source_lines();
+        let gaps = self.gaps();
 
-        let is_bounded = |i: usize, c: usize, v: &Vec<&usize>| i < line_count && c < v.len();
         let mut diff_string = String::new();
+        let mut gaps = gaps.iter();
+        for (k, op) in self.ops.iter().enumerate() {
+            match op {
+                LineOp::Context(i) => diff_string.push_str(source_lines[*i]),
+                // the whole gap is written out at its first line
+                _ if k == 0 || matches!(self.ops[k - 1], LineOp::Context(_)) => {
+                    // ignored changes read as the source did
+                    let gap = gaps.next().unwrap();
+                    if gap.ignored {
+                        diff_string.push_str(&source_lines[gap.source.clone()].concat());
+                        continue;
+                    }
+
+                    for op in self.token_script(gap) {
+                        match op {
+                            TokenOp::Common(c) => diff_string.push_str(c),
+                            TokenOp::Removed(c) => {
+                                diff_string.push_str(&red_string(&c.to_string()))
+                            }
+                            TokenOp::Added(c) => {
+                                diff_string.push_str(&green_string(&c.to_string()))
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
 
-        let parse_changes = |line_index: usize,
-                             index: &mut usize,
-                             lines: &Vec<&usize>,
-                             diff_string: &mut String,
-                             change_type: DiffCharType,
-                             source: &IndexedString| {
-            let mut contiguous_index = line_index;
-            let mut lines_skipping = 0;
-            while is_bounded(contiguous_index, *index, lines) && *lines[*index] == contiguous_index
-            {
-                let color = match change_type {
-                    DiffCharType::Addition => green,
-                    DiffCharType::Deletion => red,
-                };
+        diff_string
+    }
 
-                let char_type = match change_type {
-                    DiffCharType::Addition => '+',
-                    DiffCharType::Deletion => '-',
-                };
+    // line-oriented view, with the words or characters that actually
+    // changed highlighted within each changed line
+    pub fn to_pretty_string(&self) -> String {
+        let source_lines = self.source_lines();
+
+        // one side of a gap as (text, highlighted) pieces, a line at a time
+        let push_side = |diff_string: &mut String, pieces: &[(&str, bool)], change_type| {
+            let (color, highlight, marker): (Paint, Paint, &str) = match change_type {
+                DiffCharType::Addition => (green_string, green_highlight_string, "+"),
+                DiffCharType::Deletion => (red_string, red_highlight_string, "-"),
+            };
+
+            // neighboring pieces styled the same way are colored as one run
+            let mut line_started = false;
+            let mut run = String::new();
+            let mut run_highlighted = false;
+            let flush = |diff_string: &mut String, run: &mut String, highlighted: bool| {
+                if !run.is_empty() {
+                    match highlighted {
+                        true => diff_string.push_str(&highlight(run)),
+                        false => diff_string.push_str(&color(run)),
+                    }
+
+                    run.clear();
+                }
+            };
 
-                diff_string.push_str(&(color(char_type) + " "));
-                let line = source.get_line(*lines[*index]);
-                for c in line.chars() {
-                    diff_string.push_str(&color(c));
+            for (text, highlighted) in pieces.iter() {
+                if !line_started {
+                    diff_string.push_str(&(color(&marker.to_string()) + " "));
+                    line_started = true;
                 }
 
-                *index += 1;
-                contiguous_index += 1;
-                if change_type == DiffCharType::Deletion {
-                    lines_skipping += 1;
+                if *highlighted != run_highlighted {
+                    flush(diff_string, &mut run, run_highlighted);
+                    run_highlighted = *highlighted;
+                }
+
+                run.push_str(text.trim_end_matches(['\r', '\n']));
+                if text.ends_with('\n') {
+                    flush(diff_string, &mut run, run_highlighted);
+                    diff_string.push('\n');
+                    line_started = false;
                 }
             }
 
-            lines_skipping
+            flush(diff_string, &mut run, run_highlighted);
+            if line_started {
+                diff_string.push('\n');
+            }
         };
 
-        for i in 0..line_count {
-            let lines_skipping = parse_changes(
-                i,
-                &mut removed_index,
-                &removed_lines,
-                &mut diff_string,
-                DiffCharType::Deletion,
-                &self.source,
-            );
-
-            remained_index += lines_skipping;
-
-            parse_changes(
-                i,
-                &mut added_index,
-                &added_lines,
-                &mut diff_string,
-                DiffCharType::Addition,
-                &self.changed,
-            );
-
-            while is_bounded(i, remained_index, &remained_lines)
-                && *remained_lines[remained_index] == i
-            {
-                diff_string.push(' ');
-                let line = self.source.get_line(i);
-                diff_string.push_str(&line);
-
-                remained_index += 1;
+        let mut diff_string = String::new();
+        let gaps = self.gaps();
+        let mut gaps = gaps.iter();
+        for (k, op) in self.ops.iter().enumerate() {
+            match op {
+                LineOp::Context(i) => {
+                    diff_string.push(' ');
+                    diff_string.push_str(source_lines[*i].trim_end_matches('\n'));
+                    diff_string.push('\n');
+                }
+                // the whole gap is written out at its first line:
+                // all of the removed lines, then all of the added ones
+                _ if k == 0 || matches!(self.ops[k - 1], LineOp::Context(_)) => {
+                    // ignored changes read as the source did
+                    let gap = gaps.next().unwrap();
+                    if gap.ignored {
+                        for line in source_lines[gap.source.clone()].iter() {
+                            diff_string.push(' ');
+                            diff_string.push_str(line.trim_end_matches('\n'));
+                            diff_string.push('\n');
+                        }
+
+                        continue;
+                    }
+
+                    let script = self.token_script(gap);
+
+                    // with nothing in common, highlighting would just be noise
+                    let highlight = script.iter().any(|op| matches!(op, TokenOp::Common(_)));
+
+                    let mut removed = Vec::new();
+                    let mut added = Vec::new();
+                    for op in script {
+                        match op {
+                            TokenOp::Common(token) => {
+                                removed.push((token, false));
+                                added.push((token, false));
+                            }
+                            TokenOp::Removed(token) => removed.push((token, highlight)),
+                            TokenOp::Added(token) => added.push((token, highlight)),
+                        }
+                    }
+
+                    push_side(&mut diff_string, &removed, DiffCharType::Deletion);
+                    push_side(&mut diff_string, &added, DiffCharType::Addition);
+                }
+                _ => {}
             }
         }
 
         diff_string
     }
 
+    // (lines added, lines removed)
+    pub fn line_stats(&self) -> (usize, usize) {
+        self.ops
+            .iter()
+            .zip(self.ignored.iter())
+            .fold((0, 0), |(added, removed), (op, ignored)| match op {
+                _ if *ignored => (added, removed),
+                LineOp::Context(_) => (added, removed),
+                LineOp::Removed(_) => (added, removed + 1),
+                LineOp::Added(_) => (added + 1, removed),
+            })
+    }
+
+    // how much of the two texts is made up of unchanged lines,
+    // as a percentage of their combined length
+    pub fn similarity(&self) -> usize {
+        let total = self.source.len() + self.changed.len();
+        if total == 0 {
+            return 100;
+        }
+
+        let source_lines = self.source_lines();
+        let common = self
+            .ops
+            .iter()
+            .map(|op| match op {
+                LineOp::Context(i) => source_lines[*i].len(),
+                _ => 0,
+            })
+            .sum::<usize>();
+
+        common * 2 * 100 / total
+    }
+
+    // standard unified diff hunks, with `context_lines` of unchanged text
+    // around each change and ---/+++ headers naming the two sides
+    //
+    // empty if nothing changed
+    pub fn to_unified(&self, context_lines: usize) -> String {
+        let source_lines = self.source_lines();
+        let changed_lines = self.changed_lines();
+        let ops = &self.ops;
+
+        // group the changes into hunks, merging any whose context would overlap
+        //
+        // ignored changes never start a hunk of their own,
+        // but still show up in one that happens to cover them
+        let mut hunks: Vec<(usize, usize)> = Vec::new();
+        for (k, op) in ops.iter().enumerate() {
+            if matches!(op, LineOp::Context(_)) || self.ignored[k] {
+                continue;
+            }
+
+            let start = k.saturating_sub(context_lines);
+            let end = std::cmp::min(ops.len(), k + context_lines + 1);
+            match hunks.last_mut() {
+                Some(hunk) if start <= hunk.1 => hunk.1 = end,
+                _ => hunks.push((start, end)),
+            }
+        }
+
+        if hunks.is_empty() {
+            return String::new();
+        }
+
+        let mut unified = format!("--- {}\n+++ {}\n", self.source_name, self.changed_name);
+        let push_line = |unified: &mut String, marker: char, line: &str| {
+            unified.push(marker);
+            unified.push_str(line);
+            if !line.ends_with('\n') {
+                unified.push_str("\n\\ No newline at end of file\n");
+            }
+        };
+
+        // how many lines of each side come before each op
+        let mut lines_before = Vec::with_capacity(ops.len() + 1);
+        lines_before.push((0, 0));
+        for op in ops.iter() {
+            let (source, changed) = *lines_before.last().unwrap();
+            lines_before.push(match op {
+                LineOp::Context(_) => (source + 1, changed + 1),
+                LineOp::Removed(_) => (source + 1, changed),
+                LineOp::Added(_) => (source, changed + 1),
+            });
+        }
+
+        for (start, end) in hunks {
+            let (source_before, changed_before) = lines_before[start];
+            let (source_through, changed_through) = lines_before[end];
+
+            unified.push_str(&format!(
+                "@@ -{} +{} @@\n",
+                hunk_range(source_before, source_through - source_before),
+                hunk_range(changed_before, changed_through - changed_before)
+            ));
+
+            for op in ops[start..end].iter() {
+                match op {
+                    LineOp::Context(i) => push_line(&mut unified, ' ', source_lines[*i]),
+                    LineOp::Removed(i) => push_line(&mut unified, '-', source_lines[*i]),
+                    LineOp::Added(j) => push_line(&mut unified, '+', changed_lines[*j]),
+                }
+            }
+        }
+
+        unified
+    }
+
     pub fn print(&self) {
         println!("{}", self.to_string());
     }
+}
 
-    fn parse_source_removal(
-        &self,
-        mut source_idx: usize,
-        diff_idx: usize,
-        action: &mut dyn FnMut(char, Index) -> (),
-    ) -> usize {
-        while source_idx < self.source.len()
-            && (diff_idx >= self.lcs.len()
-                || source_idx < self.lcs[diff_idx].source_index.flat as usize)
-        {
-            if let Some(ch) = self.source.content.chars().nth(source_idx) {
-                action(ch, self.source.indices[source_idx].clone());
-            }
+// (start, length) the way unified diffs write them: 1-based, the length
+// left off when it's 1, and an empty range numbered by the line before it
+fn hunk_range(before: usize, length: usize) -> String {
+    match length {
+        0 => format!("{},0", before),
+        1 => format!("{}", before + 1),
+        _ => format!("{},{}", before + 1, length),
+    }
+}
+
+pub fn diff(source: String, changed: String, options: &Options) -> Diff {
+    let mut new_diff = Diff::new(source, changed, options);
+    new_diff.build();
 
-            source_idx += 1;
+    new_diff
+}
+
+// changed byte ranges with fewer than this many bytes in common between them are shown as one
+const HEX_MERGE_DISTANCE: usize = 8;
+const HEX_ROW_LENGTH: usize = 16;
+
+// a byte-level diff of two binary files: a hex dump of what was removed
+// and what was added in place of it, for each run of changed bytes
+pub fn hex_diff(source: &[u8], changed: &[u8]) -> String {
+    // (source range, changed range) of each run of changed bytes
+    let mut runs: Vec<(Range<usize>, Range<usize>)> = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    let matches = lcs(source, changed)
+        .into_iter()
+        .map(|pair| (pair.first, pair.second))
+        .chain(std::iter::once((source.len(), changed.len())));
+    for (next_i, next_j) in matches {
+        if next_i > i || next_j > j {
+            match runs.last_mut() {
+                Some((last_source, last_changed)) if i - last_source.end < HEX_MERGE_DISTANCE => {
+                    last_source.end = next_i;
+                    last_changed.end = next_j;
+                }
+                _ => runs.push((i..next_i, j..next_j)),
+            }
         }
 
-        source_idx
+        i = next_i + 1;
+        j = next_j + 1;
     }
 
-    fn parse_changed_addition(
-        &self,
-        mut changed_idx: usize,
-        diff_idx: usize,
-        action: &mut dyn FnMut(char, Index) -> (),
-    ) -> usize {
-        while changed_idx < self.changed.len()
-            && (diff_idx >= self.lcs.len()
-                || changed_idx < self.lcs[diff_idx].changed_index.flat as usize)
-        {
-            if let Some(ch) = self.changed.content.chars().nth(changed_idx) {
-                action(ch, self.changed.indices[changed_idx].clone());
+    // "00000010  de ad be ef  |....|"
+    let push_rows =
+        |output: &mut String, marker: char, bytes: &[u8], offset: usize, paint: Paint| {
+            for (k, row) in bytes.chunks(HEX_ROW_LENGTH).enumerate() {
+                let hex = row
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<Vec<String>>()
+                    .join(" ");
+                let ascii = row
+                    .iter()
+                    .map(|&b| {
+                        if b.is_ascii_graphic() || b == b' ' {
+                            b as char
+                        } else {
+                            '.'
+                        }
+                    })
+                    .collect::<String>();
+
+                let line = format!(
+                    "{} {:08x}  {:width$}  |{}|",
+                    marker,
+                    offset + k * HEX_ROW_LENGTH,
+                    hex,
+                    ascii,
+                    width = HEX_ROW_LENGTH * 3 - 1
+                );
+                output.push_str(&paint(&line));
+                output.push('\n');
             }
+        };
 
-            changed_idx += 1;
+    let mut output = String::new();
+    for (source_run, changed_run) in runs {
+        output.push_str(&format!(
+            "@@ -0x{:x},{} +0x{:x},{} @@\n",
+            source_run.start,
+            source_run.len(),
+            changed_run.start,
+            changed_run.len()
+        ));
+        push_rows(
+            &mut output,
+            '-',
+            &source[source_run.clone()],
+            source_run.start,
+            red_string,
+        );
+        push_rows(
+            &mut output,
+            '+',
+            &changed[changed_run.clone()],
+            changed_run.start,
+            green_string,
+        );
+    }
+
+    output
+}
+
+// the part of the edit path where the forward and backward searches meet:
+// `start` and `end` bound it, and it runs diagonally (matching) for `length`
+// steps from `diagonal`, with at most one insertion or deletion on either side
+struct Snake {
+    start: (usize, usize),
+    end: (usize, usize),
+    diagonal: (usize, usize),
+    length: usize,
+}
+
+// searches forward from the top left and backward from the bottom right
+// until the two paths overlap, on inputs with no common prefix or suffix
+fn middle_snake<T: PartialEq>(a: &[T], b: &[T], max_cost: usize) -> Snake {
+    let n = a.len() as isize;
+    let m = b.len() as isize;
+    let delta = n - m;
+    let odd = delta % 2 != 0;
+    let max = (n + m + 1) / 2;
+
+    // furthest x reached on each forward diagonal k = x - y,
+    // and furthest (lowest) y on each backward diagonal c = k - delta
+    let offset = max + 1;
+    let mut forward = vec![0isize; (2 * max + 3) as usize];
+    let mut backward = vec![m; (2 * max + 3) as usize];
+    let at = |k: isize| (k + offset) as usize;
+
+    for d in 0..=max {
+        for k in (-d..=d).rev().step_by(2) {
+            let (px, mut x) = if k == -d || (k != d && forward[at(k - 1)] < forward[at(k + 1)]) {
+                (forward[at(k + 1)], forward[at(k + 1)])
+            } else {
+                (forward[at(k - 1)], forward[at(k - 1)] + 1)
+            };
+
+            let mut y = x - k;
+            let py = if d == 0 || x != px { y } else { y - 1 };
+            let (x0, y0) = (x, y);
+            while x >= 0 && y >= 0 && x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+
+            forward[at(k)] = x;
+
+            let c = k - delta;
+            if odd && c > -d && c < d && y >= backward[at(c)] {
+                return Snake {
+                    start: (px as usize, py as usize),
+                    end: (x as usize, y as usize),
+                    diagonal: (x0 as usize, y0 as usize),
+                    length: (x - x0) as usize,
+                };
+            }
         }
 
-        changed_idx
-    }
+        for c in (-d..=d).rev().step_by(2) {
+            let (py, mut y) = if c == -d || (c != d && backward[at(c - 1)] > backward[at(c + 1)]) {
+                (backward[at(c + 1)], backward[at(c + 1)])
+            } else {
+                (backward[at(c - 1)], backward[at(c - 1)] - 1)
+            };
+
+            let k = c + delta;
+            let mut x = y + k;
+            let px = if d == 0 || y != py { x } else { x + 1 };
+            let x0 = x;
+            while x > 0 && y > 0 && x <= n && y <= m && a[x as usize - 1] == b[y as usize - 1] {
+                x -= 1;
+                y -= 1;
+            }
 
-    fn parse_common_subsequence(
-        &self,
-        mut source_idx: usize,
-        mut changed_idx: usize,
-        mut diff_idx: usize,
-        action: &mut dyn FnMut(char, Index) -> (),
-    ) -> (usize, usize, usize) {
-        while diff_idx < self.lcs.len()
-            && source_idx == self.lcs[diff_idx].source_index.flat as usize
-            && changed_idx == self.lcs[diff_idx].changed_index.flat as usize
-        {
-            if let Some(ch) = self.source.content.chars().nth(source_idx) {
-                action(ch, self.source.indices[source_idx].clone());
+            backward[at(c)] = y;
+
+            if !odd && k >= -d && k <= d && x <= forward[at(k)] {
+                return Snake {
+                    start: (x as usize, y as usize),
+                    end: (px as usize, py as usize),
+                    diagonal: (x as usize, y as usize),
+                    length: (x0 - x) as usize,
+                };
+            }
+        }
+
+        // too expensive to finish: split at whichever forward path got furthest
+        if d as usize >= max_cost {
+            let best = (-d..=d)
+                .step_by(2)
+                .map(|k| (forward[at(k)], forward[at(k)] - k))
+                .filter(|&(x, y)| x <= n && y >= 0 && y <= m && (x, y) != (n, m))
+                .max_by_key(|&(x, y)| x + y);
+
+            if let Some((x, y)) = best {
+                let point = (x as usize, y as usize);
+                return Snake {
+                    start: point,
+                    end: point,
+                    diagonal: point,
+                    length: 0,
+                };
             }
+        }
+    }
+
+    unreachable!("the forward and backward searches always meet")
+}
+
+// matched (source, changed) index pairs making up a longest common subsequence,
+// or something close to one when the inputs are very different
+pub fn lcs<T: Eq + Hash>(source: &[T], changed: &[T]) -> Vec<Pair<usize, usize>> {
+    // comparing small integers is cheaper than comparing whole lines
+    fn intern<'a, T: Eq + Hash>(tokens: &'a [T], ids: &mut HashMap<&'a T, usize>) -> Vec<usize> {
+        tokens
+            .iter()
+            .map(|t| {
+                let next = ids.len();
+                *ids.entry(t).or_insert(next)
+            })
+            .collect()
+    }
+
+    let mut ids = HashMap::new();
+    let source_ids = intern(source, &mut ids);
+    let changed_ids = intern(changed, &mut ids);
+
+    // anything that only shows up on one side can never match, so it's
+    // left out of the search entirely-- this is what keeps diffs of
+    // mostly unrelated files fast
+    let mut in_source = vec![false; ids.len()];
+    let mut in_changed = vec![false; ids.len()];
+    source_ids.iter().for_each(|&id| in_source[id] = true);
+    changed_ids.iter().for_each(|&id| in_changed[id] = true);
+
+    let source_kept = (0..source.len())
+        .filter(|&i| in_changed[source_ids[i]])
+        .collect::<Vec<usize>>();
+    let changed_kept = (0..changed.len())
+        .filter(|&j| in_source[changed_ids[j]])
+        .collect::<Vec<usize>>();
+
+    let a = source_kept
+        .iter()
+        .map(|&i| source_ids[i])
+        .collect::<Vec<usize>>();
+    let b = changed_kept
+        .iter()
+        .map(|&j| changed_ids[j])
+        .collect::<Vec<usize>>();
+
+    myers(&a, &b)
+        .into_iter()
+        .map(|pair| Pair::new(source_kept[pair.first], changed_kept[pair.second]))
+        .collect()
+}
 
-            source_idx += 1;
-            changed_idx += 1;
-            diff_idx += 1;
+fn myers<T: PartialEq>(a: &[T], b: &[T]) -> Vec<Pair<usize, usize>> {
+    let max_cost = std::cmp::max(MIN_COST_LIMIT, ((a.len() + b.len()) as f64).sqrt() as usize);
+
+    // ranges of a and b still to be compared, worked through with an explicit
+    // stack rather than recursion so long inputs can't overflow it
+    let mut pairs = Vec::new();
+    let mut stack = vec![(0..a.len(), 0..b.len())];
+    while let Some((a_range, b_range)) = stack.pop() {
+        let (a_part, b_part) = (&a[a_range.clone()], &b[b_range.clone()]);
+
+        let prefix = a_part
+            .iter()
+            .zip(b_part.iter())
+            .take_while(|(x, y)| x == y)
+            .count();
+        let suffix = a_part[prefix..]
+            .iter()
+            .rev()
+            .zip(b_part[prefix..].iter().rev())
+            .take_while(|(x, y)| x == y)
+            .count();
+
+        for i in 0..prefix {
+            pairs.push(Pair::new(a_range.start + i, b_range.start + i));
         }
 
-        (source_idx, changed_idx, diff_idx)
+        for i in 0..suffix {
+            pairs.push(Pair::new(a_range.end - 1 - i, b_range.end - 1 - i));
+        }
+
+        let a_start = a_range.start + prefix;
+        let b_start = b_range.start + prefix;
+        let a_middle = &a[a_start..a_range.end - suffix];
+        let b_middle = &b[b_start..b_range.end - suffix];
+        if a_middle.is_empty() || b_middle.is_empty() {
+            continue;
+        }
+
+        let snake = middle_snake(a_middle, b_middle, max_cost);
+        for i in 0..snake.length {
+            pairs.push(Pair::new(
+                a_start + snake.diagonal.0 + i,
+                b_start + snake.diagonal.1 + i,
+            ));
+        }
+
+        stack.push((
+            a_start..a_start + snake.start.0,
+            b_start..b_start + snake.start.1,
+        ));
+        stack.push((
+            a_start + snake.end.0..a_start + a_middle.len(),
+            b_start + snake.end.1..b_start + b_middle.len(),
+        ));
     }
+
+    pairs.sort_by_key(|pair| pair.first);
+
+    pairs
 }
 
-// how do we get the smallest possible set of changes
-// from the longest common subsequence?
-pub fn diff(source: String, changed: String) -> Diff {
-    let mut memo = vec![
-        vec![
-            Pair::new(
-                0,
-                Index {
-                    line: 0,
-                    column: 0,
-                    flat: 0
-                }
-            );
-            changed.len() + 1
-        ];
-        source.len() + 1
-    ];
-
-    let source = IndexedString::new(source);
-    let changed = IndexedString::new(changed);
-
-    let source_bytes = source.content.as_bytes();
-    let changed_bytes = changed.content.as_bytes();
-
-    let mut max_spot = Pair::new(0, 0);
-    for i in 0..source.content.len() {
-        for j in 0..changed.content.len() {
-            let c = source_bytes[i] as char;
-            let d = changed_bytes[j] as char;
-            if c == d {
-                memo[i + 1][j + 1] = Pair::new(memo[i][j].first + 1, source.indices[i].clone());
-                max_spot = Pair::new(i + 1, j + 1);
-            } else {
-                if memo[i][j + 1].first > memo[i + 1][j].first {
-                    memo[i + 1][j + 1] =
-                        Pair::new(memo[i][j + 1].first, memo[i][j + 1].second.clone());
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // xorshift, so every run sees the same inputs
+    fn generator(seed: u64) -> impl FnMut() -> u64 {
+        let mut state = seed;
+        move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        }
+    }
+
+    // the textbook quadratic table, to check myers against
+    fn lcs_length(a: &[u64], b: &[u64]) -> usize {
+        let mut table = vec![vec![0; b.len() + 1]; a.len() + 1];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                table[i][j] = if a[i] == b[j] {
+                    table[i + 1][j + 1] + 1
                 } else {
-                    memo[i + 1][j + 1] =
-                        Pair::new(memo[i + 1][j].first, memo[i + 1][j].second.clone());
-                }
+                    std::cmp::max(table[i + 1][j], table[i][j + 1])
+                };
             }
         }
+
+        table[0][0]
     }
 
-    let mut lcs = Vec::new();
+    // the pairs must match equal items, in order on both sides
+    fn assert_common_subsequence(a: &[u64], b: &[u64], pairs: &[Pair<usize, usize>]) {
+        for pair in pairs.iter() {
+            assert_eq!(a[pair.first], b[pair.second]);
+        }
 
-    // crawl the memo table to build the lcs
-    let mut i = max_spot.first as usize;
-    let mut j = max_spot.second as usize;
-    while i > 0 && j > 0 {
-        if memo[i][j].first == memo[i - 1][j].first {
-            i -= 1;
-        } else if memo[i][j].first == memo[i][j - 1].first {
-            j -= 1;
-        } else {
-            lcs.insert(
-                0,
-                LCSChar::new(
-                    source.content.chars().nth(i - 1).unwrap(),
-                    source.indices[i - 1].clone(),
-                    changed.indices[j - 1].clone(),
-                ),
-            );
-            i -= 1;
-            j -= 1;
+        for window in pairs.windows(2) {
+            assert!(window[0].first < window[1].first);
+            assert!(window[0].second < window[1].second);
         }
     }
 
-    lcs.sort_by(|a, b| a.changed_index.flat.cmp(&b.changed_index.flat));
+    fn changed(diff: &Diff, char_type: DiffCharType) -> Vec<&str> {
+        diff.diff
+            .iter()
+            .filter(|c| c.char_type == char_type)
+            .map(|c| c.value.as_str())
+            .collect()
+    }
 
-    let mut new_diff = Diff::new(source, changed, lcs);
-    new_diff.build();
+    #[test]
+    fn cjk_characters_are_compared_whole() {
+        let d = diff(
+            "你好世界\n".to_string(),
+            "你好地球\n".to_string(),
+            &Options::default(),
+        );
 
-    new_diff
+        assert_eq!(changed(&d, DiffCharType::Deletion), vec!["世", "界"]);
+        assert_eq!(changed(&d, DiffCharType::Addition), vec!["地", "球"]);
+    }
+
+    #[test]
+    fn emoji_sequences_stay_together() {
+        let d = diff(
+            "hi 👨‍👩‍👧 🇯🇵\n".to_string(),
+            "hi 👨‍👩‍👦 🇺🇸\n".to_string(),
+            &Options::default(),
+        );
+
+        assert_eq!(changed(&d, DiffCharType::Deletion), vec!["👨‍👩‍👧", "🇯🇵"]);
+        assert_eq!(changed(&d, DiffCharType::Addition), vec!["👨‍👩‍👦", "🇺🇸"]);
+    }
+
+    #[test]
+    fn combining_marks_stay_with_their_base() {
+        let d = diff(
+            "cafe\u{301}\n".to_string(),
+            "cafe\u{300}\n".to_string(),
+            &Options::default(),
+        );
+
+        assert_eq!(changed(&d, DiffCharType::Deletion), vec!["e\u{301}"]);
+        assert_eq!(changed(&d, DiffCharType::Addition), vec!["e\u{300}"]);
+    }
+
+    #[test]
+    fn indices_count_graphemes() {
+        let d = diff(
+            "ok\n日本語 e\u{301}x\n".to_string(),
+            "ok\n日本語 e\u{301}y\n".to_string(),
+            &Options::default(),
+        );
+
+        let removed = &d.diff[0];
+        assert_eq!(removed.value, "x");
+        assert_eq!(removed.index.line, 1);
+        assert_eq!(removed.index.column, 5);
+        assert_eq!(removed.index.flat, 8);
+    }
+
+    #[test]
+    fn word_granularity_compares_whole_words() {
+        let options = Options {
+            granularity: Granularity::Word,
+            ..Default::default()
+        };
+        let d = diff(
+            "the quick brown fox\n".to_string(),
+            "the quick red fox\n".to_string(),
+            &options,
+        );
+
+        assert_eq!(
+            d.to_string(),
+            "the quick \x1b[31mbrown\x1b[0m\x1b[32mred\x1b[0m fox\n"
+        );
+        assert_eq!(
+            d.to_pretty_string(),
+            "\x1b[31m-\x1b[0m \x1b[31mthe quick \x1b[0m\x1b[7;31mbrown\x1b[0m\x1b[31m fox\x1b[0m\n\
+             \x1b[32m+\x1b[0m \x1b[32mthe quick \x1b[0m\x1b[7;32mred\x1b[0m\x1b[32m fox\x1b[0m\n"
+        );
+    }
+
+    #[test]
+    fn line_granularity_leaves_lines_whole() {
+        let options = Options {
+            granularity: Granularity::Line,
+            ..Default::default()
+        };
+        let d = diff("one\ntwo\n".to_string(), "one\ntoo\n".to_string(), &options);
+
+        assert_eq!(changed(&d, DiffCharType::Deletion).concat(), "two\n");
+        assert_eq!(changed(&d, DiffCharType::Addition).concat(), "too\n");
+    }
+
+    #[test]
+    fn colored_output_is_valid_utf8() {
+        let d = diff(
+            "a👍\n".to_string(),
+            "a👎\n".to_string(),
+            &Options::default(),
+        );
+
+        assert_eq!(
+            d.to_string(),
+            "a\x1b[31m👍\x1b[0m\x1b[32m👎\x1b[0m\n".to_string()
+        );
+    }
+
+    #[test]
+    fn unified_output_keeps_multibyte_lines() {
+        let d = diff(
+            "こんにちは\n世界\nさようなら\n".to_string(),
+            "こんにちは\n地球\nさようなら\n".to_string(),
+            &Options::default(),
+        );
+
+        assert_eq!(
+            d.to_unified(1),
+            "--- a\n+++ b\n@@ -1,3 +1,3 @@\n こんにちは\n-世界\n+地球\n さようなら\n"
+        );
+    }
+
+    #[test]
+    fn whitespace_options_ignore_spacing() {
+        let old = "fn main() {\n    let x = 1;\n}\n".to_string();
+        let new = "fn main()  {\n\tlet x=1;   \n}\n".to_string();
+
+        let ignore_all_space = Options {
+            ignore_all_space: true,
+            ..Default::default()
+        };
+        assert!(diff(old.clone(), new.clone(), &ignore_all_space).is_unchanged());
+
+        // "x=1" still differs from "x = 1" when only the amount of space is ignored
+        let ignore_space_change = Options {
+            ignore_space_change: true,
+            ..Default::default()
+        };
+        let d = diff(old.clone(), new.clone(), &ignore_space_change);
+        assert!(!d.is_unchanged());
+        assert_eq!(d.line_stats(), (1, 1));
+
+        assert!(!diff(old, new, &Options::default()).is_unchanged());
+    }
+
+    #[test]
+    fn cr_at_eol_and_blank_lines_can_be_ignored() {
+        let options = Options {
+            ignore_cr_at_eol: true,
+            ..Default::default()
+        };
+        let d = diff("a\r\nb\r\n".to_string(), "a\nb\nc\n".to_string(), &options);
+        assert_eq!(d.line_stats(), (1, 0));
+        assert_eq!(d.to_unified(0), "--- a\n+++ b\n@@ -2,0 +3 @@\n+c\n");
+
+        let options = Options {
+            ignore_blank_lines: true,
+            ..Default::default()
+        };
+        let d = diff("a\nb\n".to_string(), "a\n\n\nb\n".to_string(), &options);
+        assert!(d.is_unchanged());
+        assert_eq!(d.to_unified(3), "");
+    }
+
+    #[test]
+    fn lcs_is_longest_on_every_small_input() {
+        // every pair of binary sequences up to 6 long
+        let sequences = (0..=6)
+            .flat_map(|length| {
+                (0..1u64 << length)
+                    .map(move |bits| (0..length).map(|k| bits >> k & 1).collect::<Vec<u64>>())
+            })
+            .collect::<Vec<Vec<u64>>>();
+
+        for a in sequences.iter() {
+            for b in sequences.iter() {
+                let pairs = lcs(a, b);
+                assert_common_subsequence(a, b, &pairs);
+                assert_eq!(pairs.len(), lcs_length(a, b), "{:?} {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn lcs_is_longest_on_random_inputs() {
+        let mut random = generator(0x2545f4914f6cdd1d);
+        for _ in 0..500 {
+            let alphabet = 2 + random() % 6;
+            let a = (0..random() % 40)
+                .map(|_| random() % alphabet)
+                .collect::<Vec<u64>>();
+            let b = (0..random() % 40)
+                .map(|_| random() % alphabet)
+                .collect::<Vec<u64>>();
+
+            let pairs = lcs(&a, &b);
+            assert_common_subsequence(&a, &b, &pairs);
+            assert_eq!(pairs.len(), lcs_length(&a, &b), "{:?} {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn lcs_settles_for_a_close_answer_past_the_cost_limit() {
+        // far more than MIN_COST_LIMIT edits apart
+        let mut random = generator(0x9e3779b97f4a7c15);
+        let a = (0..2000).map(|_| random() % 4).collect::<Vec<u64>>();
+        let b = (0..2000).map(|_| random() % 4).collect::<Vec<u64>>();
+
+        let pairs = lcs(&a, &b);
+        assert_common_subsequence(&a, &b, &pairs);
+
+        // short of the best, so the limit did kick in
+        let best = lcs_length(&a, &b);
+        assert!(pairs.len() < best);
+        assert!(pairs.len() * 10 >= best * 9, "{} of {}", pairs.len(), best);
+    }
+
+    // just the "@@ -0x.. +0x.. @@" line of each changed run
+    fn hex_hunks(source: &[u8], changed: &[u8]) -> Vec<String> {
+        hex_diff(source, changed)
+            .lines()
+            .filter(|line| line.starts_with("@@"))
+            .map(|line| line.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn hex_diff_of_identical_bytes_is_empty() {
+        let bytes = (0..64).collect::<Vec<u8>>();
+
+        assert_eq!(hex_diff(&bytes, &bytes), "");
+    }
+
+    #[test]
+    fn hex_diff_shows_each_changed_run() {
+        let source = (0..64).collect::<Vec<u8>>();
+        let mut changed = source.clone();
+        changed[0x10] = 0xff;
+
+        assert_eq!(hex_hunks(&source, &changed), ["@@ -0x10,1 +0x10,1 @@"]);
+        let output = hex_diff(&source, &changed);
+        assert!(output.contains("00000010  10  "));
+        assert!(output.contains("00000010  ff  "));
+    }
+
+    #[test]
+    fn hex_diff_merges_runs_closer_than_the_merge_distance() {
+        let source = (0..64).collect::<Vec<u8>>();
+
+        // HEX_MERGE_DISTANCE - 1 bytes in common between the two changes
+        let mut changed = source.clone();
+        changed[4] = 0xff;
+        changed[4 + HEX_MERGE_DISTANCE] = 0xff;
+        assert_eq!(hex_hunks(&source, &changed), ["@@ -0x4,9 +0x4,9 @@"]);
+
+        // HEX_MERGE_DISTANCE bytes in common keeps them apart
+        let mut changed = source.clone();
+        changed[4] = 0xff;
+        changed[4 + HEX_MERGE_DISTANCE + 1] = 0xff;
+        assert_eq!(
+            hex_hunks(&source, &changed),
+            ["@@ -0x4,1 +0x4,1 @@", "@@ -0xd,1 +0xd,1 @@"]
+        );
+    }
+
+    #[test]
+    fn hex_diff_of_an_insertion_at_the_end() {
+        let source = (0..16).collect::<Vec<u8>>();
+        let mut changed = source.clone();
+        changed.extend([0xaa, 0xbb]);
+
+        assert_eq!(hex_hunks(&source, &changed), ["@@ -0x10,0 +0x10,2 @@"]);
+        assert!(hex_diff(&source, &changed).contains("00000010  aa bb  "));
+    }
+
+    // not a correctness check: times a diff of two generated multi-megabyte texts
+    // with scattered edits, to run by hand with
+    // `cargo test --release large_diff_timing -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn large_diff_timing() {
+        let megabytes = 4;
+        let mut random = generator(0x2545f4914f6cdd1d);
+        let words = [
+            "fn", "let", "mut", "self", "match", "return", "0", "1", "=", "{", "}",
+        ];
+
+        let mut old = String::new();
+        let mut new = String::new();
+        while old.len() < megabytes * 1024 * 1024 {
+            let line = (0..1 + random() % 10)
+                .map(|_| words[(random() % words.len() as u64) as usize])
+                .collect::<Vec<&str>>()
+                .join(" ")
+                + "\n";
+
+            // roughly one line in a hundred is changed, dropped or added
+            match random() % 300 {
+                0 => new.push_str(&line.replace('1', "2")),
+                1 => {}
+                2 => {
+                    new.push_str("inserted\n");
+                    new.push_str(&line);
+                }
+                _ => new.push_str(&line),
+            }
+
+            old.push_str(&line);
+        }
+
+        println!("old: {} bytes, new: {} bytes", old.len(), new.len());
+
+        let start = std::time::Instant::now();
+        let file_diff = diff(old, new, &Options::default());
+        println!("diff: {:?}", start.elapsed());
+
+        let start = std::time::Instant::now();
+        let unified = file_diff.to_unified(3);
+        println!("unified: {:?} ({} bytes)", start.elapsed(), unified.len());
+
+        let (added, removed) = file_diff.line_stats();
+        println!("{} lines added, {} lines removed", added, removed);
+    }
 }
@@ -14,6 +14,7 @@ use ratatui::{
 use std::{error::Error, io};
 
 use crate::files;
+use crate::parallel;
 
 // where should these go?
 pub fn green(c: char) -> String {
@@ -32,6 +33,19 @@ pub fn red_string(s: &String) -> String {
     format!("\x1b[31m{}\x1b[0m", s)
 }
 
+// reversed, for picking out the changed parts of a line
+pub fn red_highlight_string(s: &String) -> String {
+    format!("\x1b[7;31m{}\x1b[0m", s)
+}
+
+pub fn green_highlight_string(s: &String) -> String {
+    format!("\x1b[7;32m{}\x1b[0m", s)
+}
+
+pub fn yellow_string(s: &String) -> String {
+    format!("\x1b[33m{}\x1b[0m", s)
+}
+
 struct StatefulList<T> {
     state: ListState,
     items: Vec<T>,
@@ -84,7 +98,7 @@ pub fn terminal_testing() -> Result<(), Box<dyn Error>> {
     execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
-    let items = files::get_unignored_files();
+    let items = files::get_unignored_files(parallel::default_jobs());
     let mut content_map = std::collections::HashMap::new();
     for file in items.iter() {
         let content = std::fs::read_to_string(file).unwrap();
@@ -1,13 +1,25 @@
 use sha2::digest::Update;
 use sha2::{Digest, Sha256};
 
-extern crate glob;
-use std::io::Write;
+use crate::ignore;
+use std::io::Read;
 
 #[derive(Ord, PartialOrd, Eq, PartialEq, Debug)]
 pub struct StagedFile {
     pub filename: String,
     pub hash: String,
+    pub binary: bool,
+}
+
+// how much of a file is looked at to decide whether it's binary
+const BINARY_SNIFF_LENGTH: usize = 8000;
+
+// binary files are the ones with a NUL byte near the start, the way git decides,
+// along with anything that isn't valid UTF-8 since the diff engine only handles text
+pub fn is_binary(contents: &[u8]) -> bool {
+    let sniffed = &contents[..std::cmp::min(contents.len(), BINARY_SNIFF_LENGTH)];
+
+    sniffed.contains(&0) || std::str::from_utf8(contents).is_err()
 }
 
 // TODO: I think a lot of the usage of this function is hasty and suboptimal
@@ -32,138 +44,73 @@ pub fn get_hash(content: &Vec<u8>) -> String {
         .collect::<String>()
 }
 
-pub fn get_directory_files(dir: String) -> Vec<String> {
-    let mut files = Vec::new();
-    let mut stack = Vec::new();
-    stack.push(std::path::Path::new(&dir).to_path_buf());
-    while let Some(path) = stack.pop() {
-        if path.is_dir() {
-            for entry in std::fs::read_dir(path).expect("Failed to read directory") {
-                let entry = entry.expect("Failed to read entry");
-                let entry_path = entry.path();
-                stack.push(entry_path);
-            }
-        } else {
-            files.push(normalize_filename(path.to_str().unwrap().to_string()));
+// same as `get_hash`, but reads the file in chunks rather than all at once
+pub fn hash_file(path: &str) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let count = file.read(&mut buffer)?;
+        if count == 0 {
+            break;
         }
+
+        Update::update(&mut hasher, &buffer[..count]);
     }
 
-    files
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>())
 }
 
-pub fn get_unignored_files() -> Vec<String> {
-    let gitignore = match std::fs::read_to_string(".gitignore") {
-        Ok(gi) => gi,
-        Err(_) => "".to_string(),
-    };
-
-    let mut ignore_globs = gitignore
-        .lines()
-        .filter(|line| !line.starts_with("#"))
-        .map(|line| {
-            if line.ends_with("/") {
-                "./".to_string() + line + "*"
-            } else if line.starts_with("/") {
-                "./".to_string() + &line[1..] + "/*"
-            } else if line.starts_with("./") {
-                "./".to_string() + line
-            } else {
-                "./".to_string() + line
-            }
-        })
-        .collect::<Vec<String>>();
-
-    ignore_globs.extend(vec!["./.git/*".to_string()]);
-
-    let mut unignored_files = Vec::new();
-    fn walk(dir: &std::path::Path, file_list: &mut Vec<String>, ignore_globs: &Vec<String>) {
-        for entry in std::fs::read_dir(dir).expect("Failed to read directory") {
-            let entry = entry.expect("Failed to read entry");
-            let path = entry.path();
-            if path.is_dir() {
-                walk(&path, file_list, ignore_globs);
-            } else {
-                let path_str = path.to_str().unwrap().to_string();
-                if ignore_globs.iter().any(|glob| {
-                    let pattern = glob::Pattern::new(glob).unwrap();
-                    pattern.matches(&path_str)
-                }) {
-                    continue;
-                }
-
-                file_list.push(normalize_filename(path_str));
-            }
-        }
+// every file under `dir` that isn't ignored, or just `dir` when it's a file
+pub fn get_directory_files(dir: String, jobs: usize) -> Vec<String> {
+    if !std::path::Path::new(&dir).is_dir() {
+        return vec![normalize_filename(dir)];
     }
 
-    walk(
-        std::path::Path::new("."),
-        &mut unignored_files,
-        &ignore_globs,
-    );
-
-    unignored_files
+    ignore::walk(&dir, jobs)
 }
 
-// list of filename -> content hash mappings
-// space separated, one key-value pair per line
-// example entry: "file1.txt 1234567890abcdef"
-pub fn read_staging_file() -> Vec<StagedFile> {
-    let contents = std::fs::read_to_string(".recall/staged_files").expect("Failed to read file");
-    let files = contents.split("\n").collect::<Vec<&str>>();
-
-    files
-        .iter()
-        .filter(|&f| !f.is_empty())
-        .map(|f| {
-            let parts = f.split(" ").collect::<Vec<&str>>();
-            StagedFile {
-                filename: normalize_filename(parts[0].to_string()),
-                hash: parts[1].to_string(),
-            }
-        })
-        .collect()
+pub fn get_unignored_files(jobs: usize) -> Vec<String> {
+    ignore::walk(".", jobs)
 }
 
-pub fn write_staging_file(staged_files: Vec<StagedFile>) {
-    let mut output = std::fs::OpenOptions::new()
-        .write(true)
-        .truncate(true)
-        .open(".recall/staged_files")
-        .expect("Failed to open file");
-
-    for file in staged_files {
-        output
-            .write_all(format!("{} {}\n", normalize_filename(file.filename), file.hash).as_bytes())
-            .expect("Failed to write to file");
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_is_not_binary() {
+        assert!(!is_binary(b""));
+        assert!(!is_binary(b"plain text\n"));
+        assert!(!is_binary(
+            "d\u{e9}j\u{e0} vu \u{2192} \u{1f600}\n".as_bytes()
+        ));
     }
-}
 
-// tracked_files is just a newline separated list of filenames
-pub fn is_tracked(filename: String) -> bool {
-    let contents = std::fs::read_to_string(".recall/tracked_files").expect("Failed to read file");
-    let files = contents.split("\n").collect::<Vec<&str>>();
+    #[test]
+    fn a_nul_byte_near_the_start_is_binary() {
+        assert!(is_binary(b"\0"));
+        assert!(is_binary(b"text with a \0 in it"));
 
-    files.iter().any(|&f| f == filename)
-}
+        // only the start is looked at for NULs, and past it a NUL is still valid UTF-8
+        let mut contents = vec![b'a'; BINARY_SNIFF_LENGTH];
+        contents.push(0);
+        assert!(!is_binary(&contents));
 
-pub fn add_to_tracked_files(filename: String) {
-    let mut output = std::fs::OpenOptions::new()
-        .write(true)
-        .append(true)
-        .open(".recall/tracked_files")
-        .expect("Failed to open file");
+        contents[BINARY_SNIFF_LENGTH - 1] = 0;
+        assert!(is_binary(&contents));
+    }
 
-    output
-        .write_all(format!("{}\n", normalize_filename(filename)).as_bytes())
-        .expect("Failed to write to file");
-}
+    #[test]
+    fn invalid_utf8_is_binary_wherever_it_is() {
+        assert!(is_binary(&[0xff, 0xfe]));
 
-pub fn read_tracked_files() -> Vec<String> {
-    let contents = std::fs::read_to_string(".recall/tracked_files").expect("Failed to read file");
-    contents
-        .split("\n")
-        .map(|s| s.to_string())
-        .filter(|s| !s.is_empty())
-        .collect()
+        let mut contents = vec![b'a'; BINARY_SNIFF_LENGTH * 2];
+        contents.push(0xc3);
+        assert!(is_binary(&contents));
+    }
 }
@@ -0,0 +1,600 @@
+// gitignore-style ignore rules
+//
+// rules come from .recall/info/exclude, then from the .gitignore and .recallignore
+// files in each directory, with later rules beating earlier ones and a directory's
+// own files beating its parents'. the last rule that matches a path decides it,
+// so `!pattern` can bring back something an earlier rule ignored-- except for the
+// contents of an ignored directory, which are never looked at at all
+
+use std::path::Path;
+
+use crate::parallel;
+
+const IGNORE_FILES: [&str; 2] = [".gitignore", ".recallignore"];
+const EXCLUDE_FILE: &str = ".recall/info/exclude";
+
+// always ignored, whatever the rules say
+const BUILT_IN: [&str; 2] = [".recall", ".git"];
+
+#[derive(Clone)]
+enum Token {
+    Char(char),
+    // ?
+    AnyChar,
+    // *
+    AnyRun,
+    // a leading "**/" or a "/**/" in the middle: zero or more whole directories
+    AnyDirectories,
+    // a trailing "/**": everything inside
+    AnyRest,
+    // [a-z], or [!a-z] when negated
+    Class(Vec<(char, char)>, bool),
+}
+
+#[derive(Clone)]
+pub struct Rule {
+    // where it came from, for explaining matches
+    pub source: String,
+    pub line: usize,
+    pub pattern: String,
+    pub negated: bool,
+    dir_only: bool,
+    // patterns with a slash in them match from their own directory down,
+    // and ones without match a name at any depth
+    anchored: bool,
+    // the directory the rule's file is in, "" for the top level
+    base: String,
+    tokens: Vec<Token>,
+}
+
+// [...] starting just past the '[', with how many characters it took up--
+// None if it's never closed, in which case the '[' is just a character
+fn parse_class(chars: &[char]) -> Option<(Token, usize)> {
+    let mut i = 0;
+    let negated = matches!(chars.first(), Some('!') | Some('^'));
+    if negated {
+        i += 1;
+    }
+
+    let mut ranges = Vec::new();
+    let start = i;
+    while i < chars.len() {
+        // a ']' straight after the opening bracket is part of the class
+        if chars[i] == ']' && i > start {
+            return Some((Token::Class(ranges, negated), i + 1));
+        }
+
+        let mut low = chars[i];
+        if low == '\\' && i + 1 < chars.len() {
+            i += 1;
+            low = chars[i];
+        }
+
+        if chars.get(i + 1) == Some(&'-') && chars.get(i + 2).is_some_and(|&c| c != ']') {
+            ranges.push((low, chars[i + 2]));
+            i += 3;
+        } else {
+            ranges.push((low, low));
+            i += 1;
+        }
+    }
+
+    None
+}
+
+fn tokenize(pattern: &str) -> Vec<Token> {
+    let chars = pattern.chars().collect::<Vec<char>>();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '*' => {
+                let run = chars[i..].iter().take_while(|&&c| c == '*').count();
+                let at_start = i == 0 || chars[i - 1] == '/';
+                let next = chars.get(i + run);
+                i += run;
+
+                // "**" only means something special as a whole path segment
+                if run == 2 && at_start && next == Some(&'/') {
+                    tokens.push(Token::AnyDirectories);
+                    i += 1;
+                } else if run == 2 && at_start && next.is_none() && i > 2 {
+                    tokens.push(Token::AnyRest);
+                } else {
+                    tokens.push(Token::AnyRun);
+                }
+            }
+            '?' => {
+                tokens.push(Token::AnyChar);
+                i += 1;
+            }
+            '[' => match parse_class(&chars[i + 1..]) {
+                Some((class, length)) => {
+                    tokens.push(class);
+                    i += 1 + length;
+                }
+                None => {
+                    tokens.push(Token::Char('['));
+                    i += 1;
+                }
+            },
+            '\\' if i + 1 < chars.len() => {
+                tokens.push(Token::Char(chars[i + 1]));
+                i += 2;
+            }
+            c => {
+                tokens.push(Token::Char(c));
+                i += 1;
+            }
+        }
+    }
+
+    tokens
+}
+
+fn matches(tokens: &[Token], text: &[char]) -> bool {
+    let (token, rest) = match tokens.split_first() {
+        Some(split) => split,
+        None => return text.is_empty(),
+    };
+
+    match token {
+        Token::Char(c) => text.first() == Some(c) && matches(rest, &text[1..]),
+        Token::AnyChar => text.first().is_some_and(|&c| c != '/') && matches(rest, &text[1..]),
+        Token::Class(ranges, negated) => {
+            text.first().is_some_and(|&c| {
+                c != '/' && ranges.iter().any(|&(low, high)| low <= c && c <= high) != *negated
+            }) && matches(rest, &text[1..])
+        }
+        Token::AnyRun => {
+            // never crosses into another directory
+            for i in 0..=text.len() {
+                if matches(rest, &text[i..]) {
+                    return true;
+                }
+
+                if i < text.len() && text[i] == '/' {
+                    break;
+                }
+            }
+
+            false
+        }
+        Token::AnyDirectories => {
+            matches(rest, text)
+                || (0..text.len()).any(|i| text[i] == '/' && matches(rest, &text[i + 1..]))
+        }
+        Token::AnyRest => !text.is_empty(),
+    }
+}
+
+impl Rule {
+    // None for blank lines and comments
+    fn parse(line: &str, source: &str, line_number: usize, base: &str) -> Option<Rule> {
+        let line = line.trim_end_matches('\r');
+
+        // trailing spaces don't count unless they're escaped
+        let mut pattern = line;
+        while pattern.ends_with(' ') && !pattern.ends_with("\\ ") {
+            pattern = &pattern[..pattern.len() - 1];
+        }
+
+        if pattern.is_empty() || pattern.starts_with('#') {
+            return None;
+        }
+
+        let text = pattern;
+        let negated = pattern.starts_with('!');
+        if negated {
+            pattern = &pattern[1..];
+        }
+
+        let dir_only = pattern.ends_with('/');
+        let pattern_text = pattern.trim_end_matches('/');
+        let anchored = pattern_text.contains('/');
+        let pattern_text = pattern_text.trim_start_matches('/');
+        if pattern_text.is_empty() {
+            return None;
+        }
+
+        Some(Rule {
+            source: source.to_string(),
+            line: line_number,
+            pattern: text.to_string(),
+            negated,
+            dir_only,
+            anchored,
+            base: base.to_string(),
+            tokens: tokenize(pattern_text),
+        })
+    }
+
+    // `path` is relative to the top level, without a leading "./"
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+
+        let relative = if self.base.is_empty() {
+            path
+        } else {
+            match path
+                .strip_prefix(&self.base)
+                .and_then(|p| p.strip_prefix('/'))
+            {
+                Some(relative) => relative,
+                None => return false,
+            }
+        };
+
+        let text = if self.anchored {
+            relative
+        } else {
+            relative.rsplit('/').next().unwrap_or(relative)
+        };
+
+        matches(&self.tokens, &text.chars().collect::<Vec<char>>())
+    }
+}
+
+// what decided whether a path is ignored
+pub enum Reason<'a> {
+    BuiltIn(&'static str),
+    Rule(&'a Rule),
+}
+
+#[derive(Clone)]
+pub struct Ignore {
+    // lowest precedence first
+    rules: Vec<Rule>,
+}
+
+// "./src/main.rs" -> "src/main.rs"
+fn clean(path: &str) -> &str {
+    let path = path.trim_end_matches('/');
+    let path = path.strip_prefix("./").unwrap_or(path);
+    if path == "." {
+        ""
+    } else {
+        path
+    }
+}
+
+impl Ignore {
+    // the repository-wide rules, before any directory's own files are read
+    pub fn load() -> Ignore {
+        let mut ignore = Ignore { rules: Vec::new() };
+        ignore.read_file(EXCLUDE_FILE, "");
+
+        ignore
+    }
+
+    // the rules that could apply to `path`: everything from the directories above it
+    pub fn for_path(path: &str) -> Ignore {
+        let mut ignore = Ignore::load();
+        let path = clean(path);
+        if path.is_empty() {
+            return ignore;
+        }
+
+        let components = path.split('/').collect::<Vec<&str>>();
+        for end in 0..components.len() {
+            ignore.enter_directory(&components[..end].join("/"));
+        }
+
+        ignore
+    }
+
+    fn read_file(&mut self, path: &str, base: &str) {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(_) => return,
+        };
+
+        for (k, line) in contents.lines().enumerate() {
+            if let Some(rule) = Rule::parse(line, path, k + 1, base) {
+                self.rules.push(rule);
+            }
+        }
+    }
+
+    // adds the rules from a directory's own ignore files
+    fn enter_directory(&mut self, dir: &str) {
+        for name in IGNORE_FILES {
+            let path = if dir.is_empty() {
+                name.to_string()
+            } else {
+                format!("{}/{}", dir, name)
+            };
+
+            self.read_file(&path, dir);
+        }
+    }
+
+    // the rule that decides a path on its own, without looking at its parent directories
+    fn decide(&self, path: &str, is_dir: bool) -> Option<Reason<'_>> {
+        let first = path.split('/').next().unwrap_or_default();
+        if let Some(name) = BUILT_IN.iter().find(|&&name| name == first) {
+            return Some(Reason::BuiltIn(name));
+        }
+
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| rule.matches(path, is_dir))
+            .map(Reason::Rule)
+    }
+
+    // why a path is or isn't ignored: an ignored parent directory decides it for everything
+    // inside, and otherwise it's the last matching rule, which may be a negation
+    //
+    // None when no rule mentions it
+    pub fn explain(&self, path: &str, is_dir: bool) -> Option<Reason<'_>> {
+        let path = clean(path);
+        let components = path.split('/').collect::<Vec<&str>>();
+        for end in 1..components.len() {
+            let parent = components[..end].join("/");
+            if let Some(reason) = self.decide(&parent, true) {
+                if reason.ignores() {
+                    return Some(reason);
+                }
+            }
+        }
+
+        self.decide(path, is_dir)
+    }
+
+    pub fn is_ignored(&self, path: &str, is_dir: bool) -> bool {
+        self.explain(path, is_dir).is_some_and(|r| r.ignores())
+    }
+
+    // subdirectories are walked on up to `jobs` threads, each with its own copy of the rules,
+    // and split what's left of `jobs` between them for their own subdirectories
+    fn walk_into(&mut self, dir: &str, jobs: usize) -> Vec<String> {
+        self.enter_directory(dir);
+
+        let read_path = if dir.is_empty() { "." } else { dir };
+        let mut entries = std::fs::read_dir(read_path)
+            .expect("Failed to read directory")
+            .map(|entry| entry.expect("Failed to read entry"))
+            .collect::<Vec<std::fs::DirEntry>>();
+        entries.sort_by_key(|entry| entry.file_name());
+
+        let mut kept = Vec::new();
+        for entry in entries {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let path = if dir.is_empty() {
+                name
+            } else {
+                format!("{}/{}", dir, name)
+            };
+
+            let is_dir = entry.file_type().is_ok_and(|t| t.is_dir());
+            if !self.decide(&path, is_dir).is_some_and(|r| r.ignores()) {
+                kept.push((path, is_dir));
+            }
+        }
+
+        let directories = kept.iter().filter(|(_, is_dir)| *is_dir).count();
+        let jobs_each = std::cmp::max(jobs / std::cmp::max(directories, 1), 1);
+        let rules = &*self;
+        let walked = parallel::map(&kept, jobs, |(path, is_dir)| {
+            if *is_dir {
+                rules.clone().walk_into(path, jobs_each)
+            } else {
+                vec![format!("./{}", path)]
+            }
+        });
+
+        walked.concat()
+    }
+}
+
+impl Reason<'_> {
+    pub fn ignores(&self) -> bool {
+        match self {
+            Reason::BuiltIn(_) => true,
+            Reason::Rule(rule) => !rule.negated,
+        }
+    }
+}
+
+// every file under `dir` that isn't ignored, as "./path"--
+// ignored directories are skipped over without being read
+pub fn walk(dir: &str, jobs: usize) -> Vec<String> {
+    let mut ignore = Ignore::for_path(dir);
+    let dir = clean(dir);
+    if !dir.is_empty() && ignore.is_ignored(dir, true) {
+        return Vec::new();
+    }
+
+    ignore.walk_into(dir, jobs)
+}
+
+// whether something on disk at `path` is a directory, going by the path
+// itself when there's nothing there
+pub fn is_directory(path: &str) -> bool {
+    Path::new(path).is_dir() || path.ends_with('/')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(pattern: &str) -> Rule {
+        Rule::parse(pattern, ".gitignore", 1, "").unwrap()
+    }
+
+    // (base directory, pattern) pairs, lowest precedence first
+    fn ignore(rules: &[(&str, &str)]) -> Ignore {
+        Ignore {
+            rules: rules
+                .iter()
+                .enumerate()
+                .map(|(k, (base, pattern))| Rule::parse(pattern, "test", k + 1, base).unwrap())
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn patterns_match_paths() {
+        // (pattern, path, is a directory, matches)
+        let cases = [
+            ("*.log", "a.log", false, true),
+            ("*.log", "deep/down/a.log", false, true),
+            ("*.log", "a.logs", false, false),
+            ("*.log", "logs/a", false, false),
+            ("a?c", "abc", false, true),
+            ("x/a?c", "x/a/c", false, false),
+            // a leading **/ matches at any depth, including none
+            ("**/foo", "foo", false, true),
+            ("**/foo", "a/b/foo", false, true),
+            ("**/foo", "afoo", false, false),
+            ("**/foo/bar", "x/foo/bar", false, true),
+            // a /**/ in the middle stands for zero or more directories
+            ("a/**/b", "a/b", false, true),
+            ("a/**/b", "a/x/b", false, true),
+            ("a/**/b", "a/x/y/b", false, true),
+            ("a/**/b", "a/xb", false, false),
+            ("a/**/b", "x/a/b", false, false),
+            // a trailing /** is everything inside, but not the directory itself
+            ("abc/**", "abc/x", false, true),
+            ("abc/**", "abc/x/y", false, true),
+            ("abc/**", "abc", true, false),
+            // a slash anywhere but the end anchors the pattern to its directory
+            ("/build", "build", true, true),
+            ("/build", "src/build", true, false),
+            ("doc/*.txt", "doc/a.txt", false, true),
+            ("doc/*.txt", "doc/x/a.txt", false, false),
+            ("doc/*.txt", "x/doc/a.txt", false, false),
+            ("build", "src/build", true, true),
+            // a trailing slash only matches directories
+            ("foo/", "foo", true, true),
+            ("foo/", "a/foo", true, true),
+            ("foo/", "foo", false, false),
+            // trailing spaces are dropped unless escaped
+            ("foo   ", "foo", false, true),
+            ("foo\\ ", "foo ", false, true),
+            ("foo\\ ", "foo", false, false),
+            ("\\#file", "#file", false, true),
+            ("\\!file", "!file", false, true),
+            // classes, and a [ that's never closed is just a character
+            ("[ab]c", "ac", false, true),
+            ("[ab]c", "cc", false, false),
+            ("[a-c]x", "bx", false, true),
+            ("[!a]x", "bx", false, true),
+            ("[!a]x", "ax", false, false),
+            ("[]]x", "]x", false, true),
+            ("[abc", "[abc", false, true),
+            ("[abc", "a", false, false),
+            ("a[/]b", "a/b", false, false),
+        ];
+
+        for (pattern, path, is_dir, expected) in cases {
+            assert_eq!(
+                rule(pattern).matches(path, is_dir),
+                expected,
+                "{:?} against {:?}",
+                pattern,
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn blank_lines_and_comments_are_not_rules() {
+        for line in ["", "   ", "# a comment", "#*.log", "/", "\r"] {
+            assert!(
+                Rule::parse(line, ".gitignore", 1, "").is_none(),
+                "{:?}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn negation_is_parsed_off_the_pattern() {
+        let negated = rule("!*.log");
+        assert!(negated.negated);
+        assert_eq!(negated.pattern, "!*.log");
+        assert!(negated.matches("a.log", false));
+
+        assert!(!rule("*.log").negated);
+    }
+
+    #[test]
+    fn rules_match_from_their_own_directory() {
+        let unanchored = Rule::parse("*.tmp", "sub/.gitignore", 1, "sub").unwrap();
+        assert!(unanchored.matches("sub/a.tmp", false));
+        assert!(unanchored.matches("sub/x/a.tmp", false));
+        assert!(!unanchored.matches("a.tmp", false));
+        assert!(!unanchored.matches("subway/a.tmp", false));
+
+        let anchored = Rule::parse("/only", "sub/.gitignore", 1, "sub").unwrap();
+        assert!(anchored.matches("sub/only", false));
+        assert!(!anchored.matches("sub/x/only", false));
+        assert!(!anchored.matches("only", false));
+    }
+
+    #[test]
+    fn the_last_matching_rule_wins() {
+        let rules = ignore(&[("", "*.log"), ("", "!keep.log")]);
+        assert!(rules.is_ignored("a.log", false));
+        assert!(!rules.is_ignored("keep.log", false));
+        assert!(!rules.is_ignored("a.txt", false));
+        assert!(rules.explain("a.txt", false).is_none());
+
+        let rules = ignore(&[("", "!keep.log"), ("", "*.log")]);
+        assert!(rules.is_ignored("keep.log", false));
+    }
+
+    #[test]
+    fn nested_ignore_files_beat_their_parents() {
+        let rules = ignore(&[
+            ("", "*.log"),
+            ("", "*.txt"),
+            ("sub", "!*.log"),
+            ("sub/deeper", "*.log"),
+        ]);
+
+        assert!(rules.is_ignored("a.log", false));
+        assert!(!rules.is_ignored("sub/a.log", false));
+        assert!(!rules.is_ignored("sub/other/a.log", false));
+        assert!(rules.is_ignored("sub/deeper/a.log", false));
+        assert!(rules.is_ignored("sub/a.txt", false));
+
+        match rules.explain("sub/a.log", false) {
+            Some(Reason::Rule(rule)) => assert_eq!(rule.line, 3),
+            _ => panic!("expected the nested rule to decide it"),
+        }
+    }
+
+    #[test]
+    fn nothing_inside_an_ignored_directory_comes_back() {
+        let rules = ignore(&[("", "build/"), ("", "!build/keep.txt"), ("", "!*.md")]);
+
+        assert!(rules.is_ignored("build", true));
+        assert!(rules.is_ignored("build/keep.txt", false));
+        assert!(rules.is_ignored("build/docs/a.md", false));
+        match rules.explain("build/keep.txt", false) {
+            Some(Reason::Rule(rule)) => assert_eq!(rule.pattern, "build/"),
+            _ => panic!("expected the directory's rule to decide it"),
+        }
+
+        // ignoring what's inside, rather than the directory, leaves room to bring some back
+        let rules = ignore(&[("", "build/*"), ("", "!build/keep.txt")]);
+        assert!(!rules.is_ignored("build", true));
+        assert!(rules.is_ignored("build/other.txt", false));
+        assert!(!rules.is_ignored("build/keep.txt", false));
+    }
+
+    #[test]
+    fn recall_and_git_are_always_ignored() {
+        let rules = ignore(&[("", "!.recall"), ("", "!.git/**")]);
+
+        assert!(rules.is_ignored(".recall", true));
+        assert!(rules.is_ignored("./.recall/index", false));
+        assert!(rules.is_ignored(".git/HEAD", false));
+        assert!(!rules.is_ignored(".gitignore", false));
+    }
+}
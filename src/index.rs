@@ -0,0 +1,649 @@
+// .recall/index: every tracked file, what's staged for it, and what its working copy
+// looked like the last time it was hashed
+//
+// the stat data is what keeps `status` fast on big trees-- a file whose size, mtime and
+// inode are the same as when it was last hashed still has the hash it had then,
+// so only the files that were touched get read again
+//
+// the index replaced the tracked_files and staged_files text files,
+// which get folded into a new index the first time one is needed
+
+use std::collections::BTreeMap;
+use std::time::UNIX_EPOCH;
+
+use crate::files::{self, StagedFile};
+use crate::parallel;
+use crate::storage::{self, Error, HASH_LENGTH};
+use crate::to_byte_slice;
+
+const INDEX_PATH: &str = ".recall/index";
+const LEGACY_TRACKED_PATH: &str = ".recall/tracked_files";
+const LEGACY_STAGED_PATH: &str = ".recall/staged_files";
+
+// version 2 added removals
+const MAGIC: &[u8; 4] = b"RCLI";
+const FORMAT_VERSION: u32 = 2;
+
+// entry flags
+const FLAG_STAGED: u8 = 1;
+const FLAG_BINARY: u8 = 2;
+const FLAG_REMOVED: u8 = 4;
+
+const U32_LEN: usize = std::mem::size_of::<u32>();
+const U64_LEN: usize = std::mem::size_of::<u64>();
+
+#[derive(Clone, Copy, PartialEq, Default)]
+struct Stat {
+    size: u64,
+    // since the epoch
+    mtime_seconds: u64,
+    mtime_nanos: u32,
+    inode: u64,
+}
+
+#[cfg(unix)]
+fn inode(metadata: &std::fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+
+    metadata.ino()
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &std::fs::Metadata) -> u64 {
+    0
+}
+
+impl Stat {
+    fn of(metadata: &std::fs::Metadata) -> Stat {
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+
+        Stat {
+            size: metadata.len(),
+            mtime_seconds: modified.as_secs(),
+            mtime_nanos: modified.subsec_nanos(),
+            inode: inode(metadata),
+        }
+    }
+}
+
+struct Entry {
+    // the working file as of the last time it was hashed--
+    // all zeroes, with no hash, if it hasn't been yet
+    stat: Stat,
+    hash: Option<String>,
+    staged: Option<Staged>,
+    // untracked, with the next save leaving it out-- the entry only sticks around until then
+    removed: bool,
+}
+
+struct Staged {
+    hash: String,
+    binary: bool,
+}
+
+pub struct Index {
+    entries: BTreeMap<String, Entry>,
+    // when the index was last written, down to the nanosecond
+    //
+    // a file changed in the same tick it was hashed in can have different contents
+    // with the same stat data, so only files last modified before then are trusted
+    written: (u64, u32),
+    changed: bool,
+}
+
+fn take<'a>(
+    bytes: &'a [u8],
+    cursor: &mut usize,
+    length: usize,
+    field: &'static str,
+) -> Result<&'a [u8], Error> {
+    let end = match cursor.checked_add(length) {
+        Some(end) if end <= bytes.len() => end,
+        _ => return Err(Error::Truncated(field)),
+    };
+
+    let data = &bytes[*cursor..end];
+    *cursor = end;
+
+    Ok(data)
+}
+
+fn take_u64(bytes: &[u8], cursor: &mut usize, field: &'static str) -> Result<u64, Error> {
+    let data = take(bytes, cursor, U64_LEN, field)?;
+
+    Ok(u64::from_be_bytes(data.try_into().unwrap()))
+}
+
+fn take_hash(bytes: &[u8], cursor: &mut usize, field: &'static str) -> Result<String, Error> {
+    let data = take(bytes, cursor, HASH_LENGTH, field)?;
+
+    Ok(storage::from_byte_slice(data))
+}
+
+impl Index {
+    fn empty() -> Index {
+        Index {
+            entries: BTreeMap::new(),
+            written: (0, 0),
+            changed: false,
+        }
+    }
+
+    // exits if the index can't be made sense of, since nothing can be staged or saved without it
+    pub fn read() -> Index {
+        let bytes = match std::fs::read(INDEX_PATH) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Index::from_legacy(),
+            Err(e) => {
+                eprintln!("failed to read index: {}", e);
+                std::process::exit(1);
+            }
+        };
+
+        let mut index = match Index::from_bytes(&bytes) {
+            Ok(index) => index,
+            Err(e) => {
+                eprintln!("failed to read index: {}", e);
+                std::process::exit(1);
+            }
+        };
+
+        if let Ok(metadata) = std::fs::metadata(INDEX_PATH) {
+            let stat = Stat::of(&metadata);
+            index.written = (stat.mtime_seconds, stat.mtime_nanos);
+        }
+
+        index
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Index, Error> {
+        let mut cursor = 0;
+        if take(bytes, &mut cursor, MAGIC.len(), "magic")? != MAGIC {
+            return Err(Error::UnknownFormat("index"));
+        }
+
+        let version = take(bytes, &mut cursor, U32_LEN, "version")?;
+        let version = u32::from_be_bytes(version.try_into().unwrap());
+        if version > FORMAT_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        let mut index = Index::empty();
+        let count = take_u64(bytes, &mut cursor, "entry count")?;
+        for _ in 0..count {
+            let filename_length = take_u64(bytes, &mut cursor, "filename length")?;
+            let filename_length =
+                usize::try_from(filename_length).map_err(|_| Error::TooLarge("filename length"))?;
+            let filename = take(bytes, &mut cursor, filename_length, "filename")?;
+            let filename =
+                String::from_utf8(filename.to_vec()).map_err(|_| Error::InvalidUtf8("filename"))?;
+
+            let stat = Stat {
+                size: take_u64(bytes, &mut cursor, "size")?,
+                mtime_seconds: take_u64(bytes, &mut cursor, "mtime")?,
+                mtime_nanos: u32::from_be_bytes(
+                    take(bytes, &mut cursor, U32_LEN, "mtime")?
+                        .try_into()
+                        .unwrap(),
+                ),
+                inode: take_u64(bytes, &mut cursor, "inode")?,
+            };
+
+            let flags = take(bytes, &mut cursor, 1, "flags")?[0];
+            let hash = take_hash(bytes, &mut cursor, "hash")?;
+            let staged_hash = take_hash(bytes, &mut cursor, "staged hash")?;
+
+            index.entries.insert(
+                filename,
+                Entry {
+                    stat,
+                    hash: (!hash.is_empty()).then_some(hash),
+                    staged: (flags & FLAG_STAGED != 0).then_some(Staged {
+                        hash: staged_hash,
+                        binary: flags & FLAG_BINARY != 0,
+                    }),
+                    removed: flags & FLAG_REMOVED != 0,
+                },
+            );
+        }
+
+        Ok(index)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&FORMAT_VERSION.to_be_bytes());
+        bytes.extend_from_slice(&(self.entries.len() as u64).to_be_bytes());
+        for (filename, entry) in self.entries.iter() {
+            bytes.extend_from_slice(&(filename.len() as u64).to_be_bytes());
+            bytes.extend_from_slice(filename.as_bytes());
+            bytes.extend_from_slice(&entry.stat.size.to_be_bytes());
+            bytes.extend_from_slice(&entry.stat.mtime_seconds.to_be_bytes());
+            bytes.extend_from_slice(&entry.stat.mtime_nanos.to_be_bytes());
+            bytes.extend_from_slice(&entry.stat.inode.to_be_bytes());
+
+            let mut flags = 0;
+            if let Some(staged) = &entry.staged {
+                flags |= FLAG_STAGED;
+                if staged.binary {
+                    flags |= FLAG_BINARY;
+                }
+            }
+
+            if entry.removed {
+                flags |= FLAG_REMOVED;
+            }
+
+            bytes.push(flags);
+            let hash = entry.hash.as_deref().unwrap_or_default();
+            bytes.extend_from_slice(&to_byte_slice!(hash.as_bytes(), HASH_LENGTH));
+            let staged_hash = entry.staged.as_ref().map(|s| s.hash.as_str());
+            bytes.extend_from_slice(&to_byte_slice!(
+                staged_hash.unwrap_or_default().as_bytes(),
+                HASH_LENGTH
+            ));
+        }
+
+        bytes
+    }
+
+    // written to the side and moved into place, so a crash never leaves half an index
+    pub fn write(&self) {
+        let temporary = format!("{}.tmp", INDEX_PATH);
+        std::fs::write(&temporary, self.to_bytes()).expect("Failed to write index");
+        std::fs::rename(&temporary, INDEX_PATH).expect("Failed to write index");
+    }
+
+    // only worth writing when hashes were cached along the way
+    pub fn write_if_changed(&self) {
+        if self.changed {
+            self.write();
+        }
+    }
+
+    // repositories from before the index kept a newline separated list of tracked files,
+    // and "filename hash[ binary]" lines for staged ones
+    fn from_legacy() -> Index {
+        let tracked = std::fs::read_to_string(LEGACY_TRACKED_PATH).ok();
+        let staged = std::fs::read_to_string(LEGACY_STAGED_PATH).ok();
+        let index = Index::from_legacy_lists(tracked.as_deref(), staged.as_deref());
+
+        if tracked.is_some() || staged.is_some() {
+            index.write();
+            let _ = std::fs::remove_file(LEGACY_TRACKED_PATH);
+            let _ = std::fs::remove_file(LEGACY_STAGED_PATH);
+        }
+
+        index
+    }
+
+    fn from_legacy_lists(tracked: Option<&str>, staged: Option<&str>) -> Index {
+        let mut index = Index::empty();
+        for filename in tracked
+            .unwrap_or_default()
+            .lines()
+            .filter(|l| !l.is_empty())
+        {
+            index.track(filename);
+        }
+
+        for line in staged.unwrap_or_default().lines().filter(|l| !l.is_empty()) {
+            let parts = line.split(' ').collect::<Vec<&str>>();
+            if parts.len() < 2 {
+                continue;
+            }
+
+            index.stage(StagedFile {
+                filename: parts[0].to_string(),
+                hash: parts[1].to_string(),
+                binary: parts.get(2) == Some(&"binary"),
+            });
+        }
+
+        index
+    }
+
+    pub fn tracked(&self) -> Vec<String> {
+        self.entries
+            .iter()
+            .filter(|(_, entry)| !entry.removed)
+            .map(|(filename, _)| filename.clone())
+            .collect()
+    }
+
+    // tracking a removed file again takes back the removal
+    pub fn track(&mut self, filename: &str) {
+        let entry = self
+            .entries
+            .entry(files::normalize_filename(filename.to_string()))
+            .or_insert(Entry {
+                stat: Stat::default(),
+                hash: None,
+                staged: None,
+                removed: false,
+            });
+        entry.removed = false;
+        self.changed = true;
+    }
+
+    // stops tracking a file, along with anything staged for it
+    //
+    // files the head save has are kept as removals, so the next save leaves them out--
+    // anything else is just forgotten
+    pub fn remove(&mut self, filename: &str, saved: bool) {
+        let filename = files::normalize_filename(filename.to_string());
+        if saved {
+            self.entries.insert(
+                filename,
+                Entry {
+                    stat: Stat::default(),
+                    hash: None,
+                    staged: None,
+                    removed: true,
+                },
+            );
+        } else {
+            self.entries.remove(&filename);
+        }
+
+        self.changed = true;
+    }
+
+    // files the next save leaves out
+    pub fn removed(&self) -> Vec<String> {
+        self.entries
+            .iter()
+            .filter(|(_, entry)| entry.removed)
+            .map(|(filename, _)| filename.clone())
+            .collect()
+    }
+
+    // once saved, nothing's staged and removals are done with
+    pub fn clear_staged(&mut self) {
+        self.entries.retain(|_, entry| !entry.removed);
+        for entry in self.entries.values_mut() {
+            entry.staged = None;
+        }
+
+        self.changed = true;
+    }
+
+    pub fn staged(&self) -> Vec<StagedFile> {
+        self.entries
+            .iter()
+            .filter_map(|(filename, entry)| {
+                entry.staged.as_ref().map(|staged| StagedFile {
+                    filename: filename.clone(),
+                    hash: staged.hash.clone(),
+                    binary: staged.binary,
+                })
+            })
+            .collect()
+    }
+
+    // staging a file tracks it too
+    pub fn stage(&mut self, file: StagedFile) {
+        self.track(&file.filename);
+        let filename = files::normalize_filename(file.filename);
+        if let Some(entry) = self.entries.get_mut(&filename) {
+            entry.staged = Some(Staged {
+                hash: file.hash,
+                binary: file.binary,
+            });
+        }
+    }
+
+    // replaces all the staged contents with `staged`, leaving removals alone
+    pub fn set_staged(&mut self, staged: Vec<StagedFile>) {
+        for entry in self.entries.values_mut() {
+            entry.staged = None;
+        }
+
+        for file in staged {
+            self.stage(file);
+        }
+
+        self.changed = true;
+    }
+
+    fn is_settled(&self, stat: &Stat) -> bool {
+        (stat.mtime_seconds, stat.mtime_nanos) < self.written
+    }
+
+    // the hash of a working file, reusing the cached one if the file hasn't been touched since,
+    // along with its stat data when it had to be hashed again
+    fn look_up(&self, filename: &str) -> std::io::Result<(String, Option<Stat>)> {
+        let stat = Stat::of(&std::fs::metadata(filename)?);
+        if let Some(entry) = self.entries.get(filename) {
+            if let Some(hash) = entry
+                .hash
+                .as_ref()
+                .filter(|_| entry.stat == stat && self.is_settled(&stat))
+            {
+                return Ok((hash.clone(), None));
+            }
+        }
+
+        Ok((files::hash_file(filename)?, Some(stat)))
+    }
+
+    // the hashes of a batch of working files, in order, with the ones that
+    // need reading hashed on up to `jobs` threads
+    pub fn hash_files(
+        &mut self,
+        filenames: &[String],
+        jobs: usize,
+    ) -> Vec<std::io::Result<String>> {
+        let filenames = filenames
+            .iter()
+            .map(|f| files::normalize_filename(f.clone()))
+            .collect::<Vec<String>>();
+        let index = &*self;
+        let looked_up = parallel::map(&filenames, jobs, |filename| index.look_up(filename));
+
+        let mut hashes = Vec::new();
+        for (filename, result) in filenames.iter().zip(looked_up) {
+            hashes.push(result.map(|(hash, stat)| {
+                if let Some(stat) = stat {
+                    self.remember(filename, stat, &hash);
+                }
+
+                hash
+            }));
+        }
+
+        hashes
+    }
+
+    // caches `hash` for a tracked file that was just read,
+    // so the next `status` doesn't have to read it again
+    pub fn record_hash(&mut self, filename: &str, hash: &str) {
+        if let Ok(metadata) = std::fs::metadata(filename) {
+            self.remember(filename, Stat::of(&metadata), hash);
+        }
+    }
+
+    fn remember(&mut self, filename: &str, stat: Stat, hash: &str) {
+        let filename = files::normalize_filename(filename.to_string());
+        if let Some(entry) = self.entries.get_mut(&filename) {
+            entry.stat = stat;
+            entry.hash = Some(hash.to_string());
+            self.changed = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(c: char) -> String {
+        c.to_string().repeat(HASH_LENGTH)
+    }
+
+    fn sample() -> Index {
+        let mut index = Index::empty();
+        index.track("tracked.txt");
+        index.stage(StagedFile {
+            filename: "dir/staged.bin".to_string(),
+            hash: hash('b'),
+            binary: true,
+        });
+        index.remove("gone.txt", true);
+        index.remember(
+            "tracked.txt",
+            Stat {
+                size: 12,
+                mtime_seconds: 1_700_000_000,
+                mtime_nanos: 123_456_789,
+                inode: 42,
+            },
+            &hash('a'),
+        );
+
+        index
+    }
+
+    // a file of its own in the temporary directory, removed when dropped
+    struct TempFile(String);
+
+    impl TempFile {
+        fn new(name: &str, contents: &[u8]) -> TempFile {
+            let path =
+                std::env::temp_dir().join(format!("recall-index-{}-{}", std::process::id(), name));
+            std::fs::write(&path, contents).unwrap();
+
+            TempFile(path.to_string_lossy().to_string())
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn round_trips_through_bytes() {
+        let index = sample();
+        let read = Index::from_bytes(&index.to_bytes()).unwrap();
+
+        assert_eq!(read.tracked(), index.tracked());
+        assert_eq!(read.staged(), index.staged());
+        assert_eq!(read.removed(), vec!["./gone.txt".to_string()]);
+
+        let entry = &read.entries["./tracked.txt"];
+        assert!(entry.stat == index.entries["./tracked.txt"].stat);
+        assert_eq!(entry.hash, Some(hash('a')));
+        assert!(entry.staged.is_none());
+
+        // nothing hashed yet reads back as no hash, not an empty one
+        assert_eq!(read.entries["./dir/staged.bin"].hash, None);
+        assert_eq!(read.to_bytes(), index.to_bytes());
+    }
+
+    #[test]
+    fn truncated_input_is_an_error() {
+        let bytes = sample().to_bytes();
+        for length in 0..bytes.len() {
+            match Index::from_bytes(&bytes[..length]) {
+                Err(Error::Truncated(_)) => {}
+                Err(e) => panic!("cut at {}: {}", length, e),
+                Ok(_) => panic!("cut at {}: read anyway", length),
+            }
+        }
+    }
+
+    #[test]
+    fn other_files_and_newer_versions_are_refused() {
+        let mut bytes = sample().to_bytes();
+        bytes[..MAGIC.len()].copy_from_slice(b"RCLX");
+        assert!(matches!(
+            Index::from_bytes(&bytes),
+            Err(Error::UnknownFormat(_))
+        ));
+
+        let mut bytes = sample().to_bytes();
+        bytes[MAGIC.len()..MAGIC.len() + U32_LEN]
+            .copy_from_slice(&(FORMAT_VERSION + 1).to_be_bytes());
+        assert!(matches!(
+            Index::from_bytes(&bytes),
+            Err(Error::UnsupportedVersion(_))
+        ));
+    }
+
+    #[test]
+    fn migrates_the_legacy_lists() {
+        let staged = format!(
+            "b.txt {}\nimage.png {} binary\n\nmalformed\n",
+            hash('1'),
+            hash('2')
+        );
+        let index = Index::from_legacy_lists(Some("a.txt\n\nb.txt\n"), Some(&staged));
+
+        assert_eq!(index.tracked(), vec!["./a.txt", "./b.txt", "./image.png"]);
+        assert_eq!(
+            index.staged(),
+            vec![
+                StagedFile {
+                    filename: "./b.txt".to_string(),
+                    hash: hash('1'),
+                    binary: false,
+                },
+                StagedFile {
+                    filename: "./image.png".to_string(),
+                    hash: hash('2'),
+                    binary: true,
+                },
+            ]
+        );
+        assert!(index.removed().is_empty());
+
+        assert!(Index::from_legacy_lists(None, None).tracked().is_empty());
+    }
+
+    #[test]
+    fn files_changed_since_the_index_was_written_are_hashed_again() {
+        let file = TempFile::new("settled", b"contents");
+        let stat = Stat::of(&std::fs::metadata(&file.0).unwrap());
+        let actual = files::hash_file(&file.0).unwrap();
+
+        let mut index = Index::empty();
+        index.entries.insert(
+            file.0.clone(),
+            Entry {
+                stat,
+                hash: Some(hash('c')),
+                staged: None,
+                removed: false,
+            },
+        );
+
+        // written after the file was last modified: the cached hash stands
+        index.written = (stat.mtime_seconds, stat.mtime_nanos + 1);
+        let (found, restat) = index.look_up(&file.0).unwrap();
+        assert_eq!(found, hash('c'));
+        assert!(restat.is_none());
+
+        // written in the same instant, or before: it could have changed unseen
+        for written in [
+            (stat.mtime_seconds, stat.mtime_nanos),
+            (stat.mtime_seconds.saturating_sub(1), 0),
+        ] {
+            index.written = written;
+            let (found, restat) = index.look_up(&file.0).unwrap();
+            assert_eq!(found, actual);
+            assert!(restat == Some(stat));
+        }
+
+        // and different stat data always means reading it again
+        index.written = (u64::MAX, 0);
+        index.entries.get_mut(&file.0).unwrap().stat.size += 1;
+        assert_eq!(index.look_up(&file.0).unwrap().0, actual);
+    }
+}
@@ -1,20 +1,37 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::str;
 
-use crate::storage::{Save, SaveHeaders, CREATOR_LENGTH, HASH_LENGTH};
+use crate::storage::{Save, SaveHeaders, SaveReader, CREATOR_LENGTH, HASH_LENGTH};
 
+mod delta;
 mod diff;
 mod display;
 mod files;
+mod ignore;
+mod index;
+mod merge;
 mod openai;
+mod parallel;
 mod parser;
+mod patch;
+mod renames;
 mod storage;
 
 // TODO: use references lol
 
+// unchanged lines shown around each hunk of a unified diff
+const DEFAULT_CONTEXT_LINES: usize = 3;
+
+// context lines `recall apply` will ignore by default when a hunk doesn't match exactly
+const DEFAULT_FUZZ: usize = 2;
+
+// widest a diffstat's +/- bar gets
+const MAX_STAT_BAR: usize = 50;
+
 struct Flags {
     whitelist: Vec<String>,
     only_whitelist: bool,
@@ -57,28 +74,83 @@ fn main() {
         }
         "save" => {
             init_check();
-            if args.len() < 3 {
-                eprintln!("usage: recall save [memo]");
-                return;
-            }
-
-            save(args[2].clone());
+            save(args.iter().skip(2).cloned().collect());
         }
         "print-commit" => {
             print_commit();
         }
         "status" => {
             init_check();
-            status();
+            status(args.iter().skip(2).cloned().collect());
+        }
+        "log" => {
+            init_check();
+            log(args.iter().skip(2).cloned().collect());
+        }
+        "diff" => {
+            init_check();
+            diff_command(args.iter().skip(2).cloned().collect());
+        }
+        "format-patch" => {
+            init_check();
+            format_patch(args.iter().skip(2).cloned().collect());
+        }
+        "apply" => {
+            init_check();
+            apply(args.iter().skip(2).cloned().collect());
+        }
+        "verify" => {
+            init_check();
+            verify(args.iter().skip(2).cloned().collect());
+        }
+        "upgrade" => {
+            init_check();
+            upgrade();
+        }
+        "gc" => {
+            init_check();
+            gc(args.iter().skip(2).cloned().collect());
+        }
+        "checkout" => {
+            init_check();
+            checkout(args.iter().skip(2).cloned().collect());
+        }
+        "merge" => {
+            init_check();
+            merge(args.iter().skip(2).cloned().collect());
+        }
+        "rm" => {
+            init_check();
+            rm(args.iter().skip(2).cloned().collect());
+        }
+        "mv" => {
+            init_check();
+            mv(args.iter().skip(2).cloned().collect());
+        }
+        "check-ignore" => {
+            init_check();
+            check_ignore(args.iter().skip(2).cloned().collect());
         }
         "help" => {
             eprintln!("usage: recall [command] [args]");
             eprintln!("commands:");
             eprintln!("  init");
-            eprintln!("  stage [files...]");
+            eprintln!("  stage [--all | -u] [--jobs <n>] [paths...]");
             eprintln!("  unstage [files...]");
-            eprintln!("  save [memo]");
-            eprintln!("  status");
+            eprintln!("  rm [--cached] [--force] <paths...>");
+            eprintln!("  mv <from> <to>");
+            eprintln!("  save [--jobs <n>] [memo]");
+            eprintln!("  status [--jobs <n>] [-M[<n>] | --no-renames] [-w] [-b] [--ignore-blank-lines] [--ignore-cr-at-eol]");
+            eprintln!("  log [--oneline] [-n count] [--stat] [--follow <path>] [-M[<n>] | --no-renames] [-w] [-b] [--ignore-blank-lines] [--ignore-cr-at-eol]");
+            eprintln!("  checkout [--force] [hash] [files...]");
+            eprintln!("  merge <hash>");
+            eprintln!("  check-ignore <paths...>");
+            eprintln!("  diff [--staged] [--patch [-U <lines>]] [--granularity <line|word|char>] [--hex] [-M[<n>] | --no-renames] [-w] [-b] [--ignore-blank-lines] [--ignore-cr-at-eol] [hash] [hash] [files...]");
+            eprintln!("  format-patch [-U <lines>] <hash>");
+            eprintln!("  apply [--check] [--cached] [-p <n>] [--fuzz <n>] <patch>");
+            eprintln!("  gc [--repack]");
+            eprintln!("  verify [--hashes]");
+            eprintln!("  upgrade");
         }
         _ => eprintln!("unknown command: {}", command),
     }
@@ -89,6 +161,8 @@ fn init_check() {
         eprintln!("no .recall repository found--have you initialized a repository here?");
         std::process::exit(1);
     }
+
+    storage::recover_objects();
 }
 
 fn init() {
@@ -110,22 +184,37 @@ fn init() {
     std::fs::create_dir(commits_dir).expect("Failed to create directory");
     println!("Created commits directory");
 
+    std::fs::create_dir(recall_dir.join("objects")).expect("Failed to create directory");
+    println!("Created objects directory");
+
     fn touch(path: &Path) {
         let mut file = std::fs::File::create(path).expect("Failed to create file");
         file.write_all(b"").expect("Failed to write to file");
         println!("Created file: {}", path.display());
     }
 
-    touch(&recall_dir.join("tracked_files"));
-    touch(&recall_dir.join("staged_files"));
     touch(&recall_dir.join("history"));
+
+    index::Index::read().write();
+    println!("Created file: {}", recall_dir.join("index").display());
+
+    // for ignore rules that shouldn't be shared the way .gitignore and .recallignore are
+    std::fs::create_dir(recall_dir.join("info")).expect("Failed to create directory");
+    touch(&recall_dir.join("info").join("exclude"));
+}
+
+// the value given to -j/--jobs: how many threads to walk, hash and compress files with
+fn parse_jobs(value: Option<String>) -> Option<usize> {
+    value
+        .and_then(|n| n.parse::<usize>().ok())
+        .filter(|&n| n > 0)
 }
 
-fn parse_file_args(args: Vec<String>) -> Vec<String> {
+fn parse_file_args(args: Vec<String>, jobs: usize) -> Vec<String> {
     let mut files = Vec::new();
     for arg in args.iter() {
         if std::fs::metadata(arg).map(|m| m.is_dir()).unwrap_or(false) {
-            files.extend(files::get_directory_files(arg.clone()));
+            files.extend(files::get_directory_files(arg.clone(), jobs));
         } else {
             files.push(files::normalize_filename(arg.clone()));
         }
@@ -134,51 +223,181 @@ fn parse_file_args(args: Vec<String>) -> Vec<String> {
     files
 }
 
+// stages files by path: what's staged for a path is whatever's in it now,
+// unless that's just what the head save has, in which case there's nothing to stage
+//
+// a tracked file that's gone has its removal staged, like `rm --cached` would. --all stages
+// every changed, new or removed file that isn't ignored, and -u only the tracked ones-- either
+// can be narrowed down to the paths given
 fn stage(args: Vec<String>) {
-    let files = parse_file_args(args);
-    let mut staged_files = files::read_staging_file();
-    for file in files {
-        let path = Path::new(&file);
-        if !path.exists() {
-            eprintln!("file does not exist: {}", file);
-            return;
+    let usage = "usage: recall stage [--all | -u] [--jobs <n>] [paths...]";
+
+    let mut all = false;
+    let mut update = false;
+    let mut paths = Vec::new();
+    let mut jobs = parallel::default_jobs();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-A" | "--all" => all = true,
+            "-u" | "--update" => update = true,
+            "-j" | "--jobs" => match parse_jobs(args.next()) {
+                Some(n) => jobs = n,
+                None => {
+                    eprintln!("{}", usage);
+                    return;
+                }
+            },
+            _ if arg.starts_with('-') => {
+                eprintln!("unknown option: {}", arg);
+                eprintln!("{}", usage);
+                return;
+            }
+            _ => paths.push(arg),
         }
+    }
+
+    if (all && update) || (!all && !update && paths.is_empty()) {
+        eprintln!("{}", usage);
+        return;
+    }
 
-        if !files::is_tracked(file.clone()) {
-            files::add_to_tracked_files(file.clone());
+    let mut index = index::Index::read();
+    let head_listing = read_head_listing();
+    let mut staged_files = index
+        .staged()
+        .into_iter()
+        .map(|f| (f.filename.clone(), f))
+        .collect::<BTreeMap<String, files::StagedFile>>();
+    let tracked = index.tracked().into_iter().collect::<HashSet<String>>();
+
+    let files = if all || update {
+        let paths = paths
+            .iter()
+            .map(|p| normalize_path_arg(p))
+            .collect::<Vec<String>>();
+        let mut candidates = tracked.iter().cloned().collect::<Vec<String>>();
+        if all {
+            candidates.extend(files::get_unignored_files(jobs));
         }
 
-        let contents = std::fs::read(&file).expect("Failed to read file");
-        let file_hash = files::get_hash(&contents);
+        candidates.sort();
+        candidates.dedup();
+        candidates.retain(|f| matches_paths(f, &paths));
+
+        // only the ones that changed are worth reading in full--
+        // anything that can't be hashed is gone, and so gets its removal staged
+        let hashes = index.hash_files(&candidates, jobs);
+        candidates
+            .into_iter()
+            .zip(hashes)
+            .filter(|(filename, hash)| match hash {
+                Ok(hash) => {
+                    let current = staged_files
+                        .get(filename)
+                        .map(|f| f.hash.clone())
+                        .or_else(|| head_listing.get(filename).map(|h| h.content_hash()));
+                    current.as_ref() != Some(hash)
+                }
+                Err(_) => true,
+            })
+            .map(|(filename, _)| filename)
+            .collect()
+    } else {
+        // naming a directory covers the tracked files that are gone from it, too
+        let directories = paths
+            .iter()
+            .filter(|p| Path::new(p).is_dir())
+            .map(|p| normalize_path_arg(p))
+            .collect::<Vec<String>>();
+        let mut gone = tracked
+            .iter()
+            .filter(|f| !directories.is_empty() && matches_paths(f, &directories))
+            .filter(|f| !Path::new(f).exists())
+            .cloned()
+            .collect::<Vec<String>>();
+        gone.sort();
+
+        let mut files = parse_file_args(paths, jobs);
+        files.extend(gone);
+        files
+    };
 
-        let mut added = false;
-        for staged_file in staged_files.iter_mut() {
-            // ignore staged files without any pending changes
-            if file_hash == staged_file.hash {
-                added = true;
+    // the staged contents go straight into the object store,
+    // so they can still be diffed after the working file changes
+    //
+    // reading, hashing and compressing is the slow part, so it's all done up front across
+    // threads, and the results are gone through in the order the files were given
+    let stored = parallel::map(&files, jobs, |file| -> std::io::Result<(String, bool)> {
+        let contents = std::fs::read(file)?;
+        let base = head_listing.get(file).map(|h| h.content_hash());
+
+        Ok((
+            storage::write_object(&contents, base.as_deref()),
+            files::is_binary(&contents),
+        ))
+    });
+
+    // a path that can't be staged doesn't hold up the rest
+    let mut failed = false;
+    for (file, stored) in files.into_iter().zip(stored) {
+        let (file_hash, binary) = match stored {
+            Ok(stored) => stored,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && tracked.contains(&file) => {
+                staged_files.remove(&file);
+                index.remove(&file, head_listing.contains_key(&file));
                 continue;
-            } else if file == staged_file.filename {
-                added = true;
-                staged_file.hash = file_hash.clone();
             }
-        }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                eprintln!("file does not exist: {}", file);
+                failed = true;
+                continue;
+            }
+            Err(e) => {
+                eprintln!("failed to read {}: {}", file, e);
+                failed = true;
+                continue;
+            }
+        };
 
-        if !added {
-            staged_files.push(files::StagedFile {
-                filename: file.clone(),
-                hash: file_hash,
-            });
+        index.track(&file);
+        index.record_hash(&file, &file_hash);
+
+        if head_listing.get(&file).map(|h| h.content_hash()) == Some(file_hash.clone()) {
+            staged_files.remove(&file);
+        } else {
+            staged_files.insert(
+                file.clone(),
+                files::StagedFile {
+                    filename: file,
+                    hash: file_hash,
+                    binary,
+                },
+            );
         }
     }
 
-    files::write_staging_file(staged_files);
+    index.set_staged(staged_files.into_values().collect());
+    index.write();
+
+    if failed {
+        std::process::exit(1);
+    }
 }
 
 // remove a file (or multiple) from the list of staged files
+//
+// unstaging a removed file tracks it again
 fn unstage(files: Vec<String>) {
-    let files = parse_file_args(files);
-    let mut staged_files = files::read_staging_file();
+    let files = parse_file_args(files, parallel::default_jobs());
+    let mut index = index::Index::read();
+    let mut staged_files = index.staged();
+    let removed_files = index.removed();
     for file in files {
+        if removed_files.contains(&file) {
+            index.track(&file);
+        }
+
         for (index, staged_file) in staged_files.iter().enumerate() {
             if staged_file.filename == file {
                 staged_files.remove(index);
@@ -187,7 +406,204 @@ fn unstage(files: Vec<String>) {
         }
     }
 
-    files::write_staging_file(staged_files);
+    index.set_staged(staged_files);
+    index.write();
+}
+
+// "dir/" and "./dir" both name "./dir"
+fn normalize_path_arg(path: &str) -> String {
+    files::normalize_filename(path.trim_end_matches('/').to_string())
+}
+
+// stops tracking files, deleting them from the working tree as well unless --cached is given
+//
+// files in the head save are left out of the next one, which is how deletions get saved.
+// a directory stands for every tracked file under it
+fn rm(args: Vec<String>) {
+    let usage = "usage: recall rm [--cached] [--force] <paths...>";
+
+    let mut cached = false;
+    let mut force = false;
+    let mut paths = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--cached" => cached = true,
+            "-f" | "--force" => force = true,
+            _ if arg.starts_with('-') => {
+                eprintln!("unknown option: {}", arg);
+                eprintln!("{}", usage);
+                return;
+            }
+            _ => paths.push(normalize_path_arg(&arg)),
+        }
+    }
+
+    if paths.is_empty() {
+        eprintln!("{}", usage);
+        return;
+    }
+
+    let mut index = index::Index::read();
+    let head_listing = read_head_listing();
+    let tracked = index.tracked();
+    let mut selected = Vec::new();
+    for path in paths.iter() {
+        let matched = tracked
+            .iter()
+            .filter(|f| matches_paths(f, std::slice::from_ref(path)))
+            .cloned()
+            .collect::<Vec<String>>();
+        if matched.is_empty() {
+            eprintln!("not tracked: {}", path);
+            std::process::exit(1);
+        }
+
+        selected.extend(matched);
+    }
+
+    selected.sort();
+    selected.dedup();
+
+    // nothing that only exists in the working tree or the staged files gets thrown away
+    // without --force: --cached only drops what's staged, while a plain rm drops both
+    if !force {
+        let staged = index
+            .staged()
+            .into_iter()
+            .map(|f| (f.filename, f.hash))
+            .collect::<HashMap<String, String>>();
+
+        let mut at_risk = Vec::new();
+        for filename in selected.iter() {
+            let saved = head_listing.get(filename).map(|h| h.content_hash());
+            let staged = staged.get(filename).cloned();
+            let current = files::hash_file(filename).ok();
+
+            let staged_lost = staged.is_some() && staged != saved && (!cached || staged != current);
+            let working_lost =
+                !cached && current.is_some() && current != staged.clone().or(saved.clone());
+            if staged_lost || working_lost {
+                at_risk.push(filename.clone());
+            }
+        }
+
+        if !at_risk.is_empty() {
+            eprintln!("the following files have changes that haven't been saved:");
+            for file in at_risk.iter() {
+                eprintln!("  {}", display::red_string(file));
+            }
+
+            eprintln!("use --force to remove them anyway");
+            std::process::exit(1);
+        }
+    }
+
+    for filename in selected {
+        index.remove(&filename, head_listing.contains_key(&filename));
+        if !cached {
+            remove_working_file(&filename);
+        }
+
+        println!("removed {}", filename);
+    }
+
+    index.write();
+}
+
+// moves a tracked file or directory, in the working tree and in the index
+//
+// what was staged for the old path (or its saved contents, if nothing was) is staged for
+// the new one, and the old path is removed, so the next save records the move
+fn mv(args: Vec<String>) {
+    let usage = "usage: recall mv <from> <to>";
+
+    let (from, to) = match args.as_slice() {
+        [from, to] if !from.starts_with('-') && !to.starts_with('-') => {
+            (normalize_path_arg(from), normalize_path_arg(to))
+        }
+        _ => {
+            eprintln!("{}", usage);
+            return;
+        }
+    };
+
+    // moving into a directory that's already there keeps the name
+    let to = match Path::new(&from).file_name() {
+        Some(name) if Path::new(&to).is_dir() => format!("{}/{}", to, name.to_string_lossy()),
+        _ => to,
+    };
+
+    let mut index = index::Index::read();
+    let moving = index
+        .tracked()
+        .into_iter()
+        .filter(|f| matches_paths(f, std::slice::from_ref(&from)))
+        .collect::<Vec<String>>();
+    if moving.is_empty() {
+        eprintln!("not tracked: {}", from);
+        std::process::exit(1);
+    }
+
+    if !Path::new(&from).exists() {
+        eprintln!("{} doesn't exist", from);
+        std::process::exit(1);
+    }
+
+    if Path::new(&to).exists() {
+        eprintln!("{} already exists", to);
+        std::process::exit(1);
+    }
+
+    if matches_paths(&to, std::slice::from_ref(&from)) {
+        eprintln!("can't move {} inside itself", from);
+        std::process::exit(1);
+    }
+
+    if let Some(parent) = Path::new(&to).parent() {
+        std::fs::create_dir_all(parent).expect("Failed to create directory");
+    }
+
+    if let Err(e) = std::fs::rename(&from, &to) {
+        eprintln!("failed to move {}: {}", from, e);
+        std::process::exit(1);
+    }
+
+    remove_empty_parents(&from);
+
+    let head_listing = read_head_listing();
+    let staged = index
+        .staged()
+        .into_iter()
+        .map(|f| (f.filename.clone(), f))
+        .collect::<HashMap<String, files::StagedFile>>();
+    for filename in moving {
+        let moved = format!("{}{}", to, &filename[from.len()..]);
+        let contents = match staged.get(&filename) {
+            Some(staged) => Some((staged.hash.clone(), staged.binary)),
+            None => head_listing.get(&filename).map(|h| {
+                // saves from before the flag was recorded need the contents looked at
+                let binary = h.binary.unwrap_or_else(|| {
+                    storage::read_object(&h.content_hash()).is_ok_and(|c| files::is_binary(&c))
+                });
+
+                (h.content_hash(), binary)
+            }),
+        };
+
+        index.remove(&filename, head_listing.contains_key(&filename));
+        match contents {
+            Some((hash, binary)) => index.stage(files::StagedFile {
+                filename: moved.clone(),
+                hash,
+                binary,
+            }),
+            None => index.track(&moved),
+        }
+
+        println!("renamed {} -> {}", filename, moved);
+    }
+
+    index.write();
 }
 
 fn get_head() -> String {
@@ -200,155 +616,2682 @@ fn get_head() -> String {
         .to_string()
 }
 
-fn status() {
-    let all_unignored_files = files::get_unignored_files();
-    let tracked_files = files::read_tracked_files();
-    let untracked_files: Vec<String> = all_unignored_files
-        .iter()
-        .filter(|f| !tracked_files.contains(f))
-        .map(|f| f.clone())
-        .collect();
-    let staged_files = files::read_staging_file();
+fn read_history() -> Vec<String> {
+    let history = std::fs::read_to_string(".recall/history").expect("Failed to read file");
 
-    // presumably, if there's no last commit,
-    // then the tracked files will be empty
-    //
-    // TODO: pending commit implementation
-    //
-    let head = get_head();
-    let head_path = Path::new(".recall/commits").join(head);
-    let head_contents = std::fs::read(&head_path).expect("Failed to read file");
-    let head_save = Save::from_bytes(&head_contents);
-    let mut tracked_changed_files = Vec::new();
-    for tracked_file in tracked_files {
-        let contents = std::fs::read(&tracked_file).expect("Failed to read file");
-        let file_hash = files::get_hash(&contents);
-
-        match head_save.blob.get_file(&tracked_file) {
-            Some(file_contents) => {
-                let head_file_hash = files::get_hash(&file_contents);
-                if file_hash != head_file_hash {
-                    tracked_changed_files.push(tracked_file.clone());
-                }
-            }
-            None => {
-                println!("file not found in head commit: {}", tracked_file);
-            }
+    history
+        .lines()
+        .filter(|l| !l.is_empty())
+        .map(|l| l.to_string())
+        .collect()
+}
+
+fn read_save(hash: &str) -> Save {
+    let save_path = Path::new(".recall/commits").join(hash);
+    let contents = match std::fs::read(&save_path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            eprintln!("failed to read save {}: {}", hash, e);
+            std::process::exit(1);
         }
+    };
+
+    if storage::read_version(&contents, &mut 0).ok() == Some(storage::LEGACY_VERSION) {
+        report_needs_upgrade(hash);
     }
 
-    if !staged_files.is_empty() {
-        println!("Staged files:");
-        for staged_file in staged_files.iter() {
-            println!("  {}", display::green_string(&staged_file.filename));
-        }
+    match Save::from_bytes(&contents) {
+        Ok(save) => save,
+        Err(e) => report_corruption(hash, e),
     }
+}
 
-    if !tracked_changed_files.is_empty() {
-        for tracked_file in tracked_changed_files.iter() {
-            if staged_files.iter().any(|f| f.filename == *tracked_file) {
-                continue;
-            }
+// for reading through a save's file listing without loading all of it at once
+fn open_save(hash: &str) -> SaveReader {
+    let save_path = Path::new(".recall/commits").join(hash);
+    if !save_path.exists() {
+        eprintln!("failed to read save {}: file not found", hash);
+        std::process::exit(1);
+    }
 
-            println!("  {}", display::green_string(&tracked_file));
+    match SaveReader::open(&save_path) {
+        Ok(reader) if reader.version == storage::LEGACY_VERSION => report_needs_upgrade(hash),
+        Ok(reader) => reader,
+        Err(e) => report_corruption(hash, e),
+    }
+}
+
+// every file header in the save, keyed by filename
+fn read_save_listing(hash: &str) -> HashMap<String, storage::FileHeaders> {
+    let mut listing = HashMap::new();
+    for header in open_save(hash) {
+        match header {
+            Ok(header) => listing.insert(header.filename.clone(), header),
+            Err(e) => report_corruption(hash, e),
+        };
+    }
+
+    listing
+}
+
+// the head save's listing, or nothing if there are no saves yet
+fn read_head_listing() -> HashMap<String, storage::FileHeaders> {
+    match read_history().last() {
+        Some(head) => read_save_listing(head),
+        None => HashMap::new(),
+    }
+}
+
+// a path selects either the file itself or everything underneath it
+fn matches_paths(filename: &str, paths: &[String]) -> bool {
+    paths.is_empty()
+        || paths
+            .iter()
+            .any(|p| filename == p || filename.starts_with(&format!("{}/", p)))
+}
+
+// deletes a file from the working tree, along with any directories that leaves empty
+fn remove_working_file(filename: &str) {
+    match std::fs::remove_file(filename) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => {
+            eprintln!("failed to remove {}: {}", filename, e);
+            std::process::exit(1);
         }
     }
 
-    println!();
+    remove_empty_parents(filename);
+}
 
-    println!("Untracked files:");
-    for untracked_file in untracked_files.iter() {
-        if staged_files.iter().any(|f| f.filename == *untracked_file) {
-            continue;
+// clears away directories left empty above a path that's gone
+fn remove_empty_parents(path: &str) {
+    let mut dir = Path::new(path).parent();
+    while let Some(parent) = dir {
+        if parent.as_os_str().is_empty() || parent == Path::new(".") {
+            break;
         }
 
-        println!("  {}", display::red_string(&untracked_file));
+        // only succeeds on empty directories
+        if std::fs::remove_dir(parent).is_err() {
+            break;
+        }
+
+        dir = parent.parent();
     }
 }
 
-fn save(memo: String) {
-    let staged_files = files::read_staging_file();
-    let files: Vec<String> = staged_files.iter().map(|f| f.filename.clone()).collect();
-    let blob = storage::blobify(files);
+// streams a file out of the object store onto disk
+//
+// the contents go to a temporary file next to it first, and only replace the working
+// file once they've all come out intact--a bad object never costs what was there
+fn restore_file(header: &storage::FileHeaders) -> Result<(), storage::Error> {
+    let path = Path::new(&header.filename);
+    if files::hash_file(&header.filename).is_ok_and(|hash| hash == header.content_hash()) {
+        return Ok(());
+    }
 
-    // as a byte string
-    let now = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .expect("Failed to get time")
-        .as_micros();
+    let mut object = storage::open_object(&header.content_hash())?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).expect("Failed to create directory");
+    }
 
-    let hash = files::get_hash(&now.to_string().as_bytes().to_vec());
+    let temp_path = format!("{}.recall.tmp", header.filename);
+    let mut file = std::fs::File::create(&temp_path).expect("Failed to create file");
+    // not every kind of damage stops the object decompressing, so the contents are checked too
+    let copied = std::io::copy(&mut object, &mut file).is_ok()
+        && files::hash_file(&temp_path).is_ok_and(|hash| hash == header.content_hash());
+    if !copied {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(storage::Error::CorruptObject(header.content_hash()));
+    }
 
-    let memo_size = memo.len();
-    println!("memo size: {}", memo_size);
-    let headers = SaveHeaders {
-        hash: to_byte_slice!(hash.as_bytes(), HASH_LENGTH),
-        memo,
-        memo_size,
-        created_date: now,
-        creator: to_byte_slice!("recall".as_bytes(), CREATOR_LENGTH),
-    };
+    let last_modified =
+        std::time::UNIX_EPOCH + std::time::Duration::from_micros(header.last_modified as u64);
+    file.set_modified(last_modified)
+        .expect("Failed to set modification time");
 
-    let save = Save { headers, blob };
+    // the working file keeps its permissions
+    if let Ok(metadata) = std::fs::metadata(path) {
+        let _ = std::fs::set_permissions(&temp_path, metadata.permissions());
+    }
 
-    // write save bytes to file named with the hash
-    let save_bytes = save.to_bytes();
-    let save_path = Path::new(".recall/commits").join(hash.clone());
-    let mut save_file = std::fs::File::create(save_path).expect("Failed to create file");
-    save_file
-        .write_all(&save_bytes)
-        .expect("Failed to write to file");
+    std::fs::rename(&temp_path, path).expect("Failed to write file");
 
-    files::write_staging_file(Vec::new());
+    Ok(())
+}
 
-    let mut history_file = std::fs::OpenOptions::new()
-        .write(true)
-        .append(true)
-        .open(".recall/history")
-        .expect("Failed to open file");
-    history_file
-        .write_all(format!("{}\n", hash).as_bytes())
-        .expect("Failed to write to file");
+// the first 7 characters of a hash, or all of it if a damaged save left it shorter
+fn short_hash(hash: &str) -> &str {
+    hash.get(..7).unwrap_or(hash)
 }
 
-// this is just a testing function
-fn print_commit() {
-    let commit_path = Path::new(".recall/commits");
-    let commit_files = std::fs::read_dir(commit_path).expect("Failed to read directory");
-    for entry in commit_files {
-        let entry = entry.expect("Failed to read entry");
-        let path = entry.path();
+fn report_corruption(hash: &str, e: storage::Error) -> ! {
+    eprintln!("save {} is corrupt: {}", hash, e);
+    eprintln!("run `recall verify` for a full report");
+    std::process::exit(1);
+}
 
-        println!("commit: {}", path.display());
-        let mut file = std::fs::File::open(path).expect("Failed to open file");
-        let mut contents = Vec::new();
-        file.read_to_end(&mut contents)
-            .expect("Failed to read file");
+// merges used to be kept in .recall/merges, and until upgrade folds them into the saves
+// the saves they brought in look like nothing leads to them
+fn check_merges_upgraded() {
+    if Path::new(".recall/merges").exists() {
+        eprintln!("merges were recorded by an older version of recall");
+        eprintln!("run `recall upgrade` to bring them up to date");
+        std::process::exit(1);
+    }
+}
 
-        let save = Save::from_bytes(&contents);
-        println!("hash: {:?}", save.headers.hash);
-        println!("memo: {}", save.headers.memo);
-        println!("created: {:?}", save.headers.created_date);
-        println!("creator: {:?}", save.headers.creator);
-        println!("files:");
-        for header in save.blob.headers.iter() {
-            println!("  {:?}", header.filename);
-            println!(
-                "  location, length: {}, {}",
-                header.content_location, header.content_length
-            );
+// version 0 saves only hold part of the tree, so nothing but upgrade reads them
+fn report_needs_upgrade(hash: &str) -> ! {
+    eprintln!("save {} was written by an older version of recall", hash);
+    eprintln!("run `recall upgrade` to bring it up to date");
+    std::process::exit(1);
+}
+
+// resolves a (possibly abbreviated) save hash against the history
+fn resolve_hash(prefix: &str) -> Result<String, String> {
+    // saves copied in from somewhere else are in commits/ without being in the history
+    let mut saves = read_history();
+    if let Ok(entries) = std::fs::read_dir(".recall/commits") {
+        saves.extend(
+            entries
+                .flatten()
+                .map(|e| e.file_name().to_string_lossy().to_string()),
+        );
+    }
 
-            let content_bytes = &save.blob.data
-                [header.content_location..header.content_location + header.content_length];
+    let mut matches = saves
+        .into_iter()
+        .filter(|h| h.starts_with(prefix))
+        .collect::<Vec<String>>();
+    matches.sort();
+    matches.dedup();
+
+    match matches.len() {
+        0 => Err(format!("no save found matching: {}", prefix)),
+        1 => Ok(matches[0].clone()),
+        _ => Err(format!("ambiguous hash: {}", prefix)),
+    }
+}
 
-            let content = String::from_utf8(content_bytes.to_vec());
-            match content {
-                Ok(content) => println!("    {}", content),
-                Err(_) => println!("    <binary>"),
-            }
-        }
+// "512 B", "1.5 KiB", "3.2 MiB"
+fn format_size(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
 
-        println!("data length: {}", save.blob.data.len());
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+fn format_date(micros: u128) -> String {
+    match chrono::DateTime::from_timestamp_micros(micros as i64) {
+        Some(date) => date
+            .with_timezone(&chrono::Local)
+            .format("%a %b %e %H:%M:%S %Y %z")
+            .to_string(),
+        None => format!("<invalid date: {}>", micros),
+    }
+}
+
+// walks the history from the most recent save backwards
+//
+// --follow only shows the saves that changed one file,
+// picking up its old name wherever it was renamed or copied
+fn log(args: Vec<String>) {
+    let usage = "usage: recall log [--oneline] [-n count] [--stat] [--follow <path>] [-M[<n>] | --no-renames] [-w] [-b] [--ignore-blank-lines] [--ignore-cr-at-eol]";
+
+    let mut options = diff::Options::default();
+    let mut rename_threshold = Some(renames::DEFAULT_THRESHOLD);
+    let mut oneline = false;
+    let mut stat = false;
+    let mut count = usize::MAX;
+    let mut following = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--oneline" => oneline = true,
+            "--stat" => stat = true,
+            "-n" => match args.next().map(|n| n.parse::<usize>()) {
+                Some(Ok(n)) => count = n,
+                _ => {
+                    eprintln!("{}", usage);
+                    return;
+                }
+            },
+            "--follow" => match args.next() {
+                Some(path) => {
+                    following = Some(files::normalize_filename(
+                        path.trim_end_matches('/').to_string(),
+                    ))
+                }
+                None => {
+                    eprintln!("{}", usage);
+                    return;
+                }
+            },
+            _ if parse_ignore_option(arg, &mut options) => {}
+            _ if parse_rename_option(arg, &mut rename_threshold) => {}
+            _ => {
+                eprintln!("unknown option: {}", arg);
+                eprintln!("{}", usage);
+                return;
+            }
+        }
+    }
+
+    // follow the parent links back from the head save
+    let mut next = read_history().last().cloned();
+    let mut index = 0;
+    while let Some(hash) = next {
+        if index >= count {
+            break;
+        }
+
+        let save = open_save(&hash);
+        next = save.headers.parent();
+
+        // what changed since the parent, only worked out when something needs it
+        let parent_changes = || {
+            let old = match &next {
+                Some(parent) => listing_versions(read_save_listing(parent)),
+                None => BTreeMap::new(),
+            };
+            let new = listing_versions(read_save_listing(&hash));
+
+            (old, new)
+        };
+
+        let mut changes = None;
+        if let Some(path) = &following {
+            let (old, new) = parent_changes();
+            if old.get(path) == new.get(path) {
+                continue;
+            }
+
+            // a file that's new in this save might have come from somewhere else
+            let mut file_changes = changed_files(&old, &new, DiffSource::ObjectStore, &[]);
+            if !old.contains_key(path) {
+                file_changes = find_renames(file_changes, rename_threshold);
+            }
+
+            file_changes.retain(|c| c.filename == *path);
+            match file_changes.first().map(|c| (&c.origin, &c.old)) {
+                Some((Some(origin), _)) => following = Some(origin.filename.clone()),
+                // there's nothing further back to follow
+                Some((None, None)) => next = None,
+                _ => {}
+            }
+
+            changes = Some(file_changes);
+        } else if stat {
+            let (old, new) = parent_changes();
+            let file_changes = changed_files(&old, &new, DiffSource::ObjectStore, &[]);
+            changes = Some(find_renames(file_changes, rename_threshold));
+        }
+
+        let save_hash = storage::from_byte_slice(&save.headers.hash);
+        let memo_summary = save.headers.memo.lines().next().unwrap_or("").to_string();
+
+        if oneline {
+            println!(
+                "{} {}",
+                display::yellow_string(&short_hash(&save_hash).to_string()),
+                memo_summary
+            );
+        } else {
+            if index > 0 {
+                println!();
+            }
+
+            println!("{}", display::yellow_string(&format!("save {}", save_hash)));
+            println!(
+                "Creator: {}",
+                storage::from_byte_slice(&save.headers.creator)
+            );
+            println!("Date:    {}", format_date(save.headers.created_date));
+            println!();
+            for line in save.headers.memo.lines() {
+                println!("    {}", line);
+            }
+        }
+
+        if !oneline {
+            println!();
+        }
+
+        if stat {
+            print_diffstat(changes.as_deref().unwrap_or_default(), &options);
+        } else if let Some(changes) = &changes {
+            if !oneline {
+                for change in changes.iter() {
+                    println!("    {}", change_name(change));
+                }
+            }
+        } else if !oneline {
+            // the listing is streamed, so even huge saves print in bounded memory
+            for header in save {
+                match header {
+                    Ok(header) => println!("    {}", header.filename),
+                    Err(e) => report_corruption(&hash, e),
+                }
+            }
+        }
+
+        index += 1;
+    }
+}
+
+fn checkout(args: Vec<String>) {
+    let usage = "usage: recall checkout [--force] [hash] [files...]";
+
+    let mut force = false;
+    let mut positional = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--force" | "-f" => force = true,
+            _ => positional.push(arg),
+        }
+    }
+
+    if positional.is_empty() {
+        eprintln!("{}", usage);
+        return;
+    }
+
+    let hash = match resolve_hash(&positional[0]) {
+        Ok(hash) => hash,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let paths = positional[1..]
+        .iter()
+        .map(|p| files::normalize_filename(p.trim_end_matches('/').to_string()))
+        .collect::<Vec<String>>();
+
+    let mut selected = Vec::new();
+    for header in open_save(&hash) {
+        let header = match header {
+            Ok(header) => header,
+            Err(e) => report_corruption(&hash, e),
+        };
+
+        if matches_paths(&header.filename, &paths) {
+            selected.push(header);
+        }
+    }
+
+    for path in paths.iter() {
+        if !selected
+            .iter()
+            .any(|h| matches_paths(&h.filename, std::slice::from_ref(path)))
+        {
+            eprintln!("file not found in save {}: {}", &hash[..7], path);
+            std::process::exit(1);
+        }
+    }
+
+    // working files whose contents are neither in the head save nor the target save
+    // would be lost for good, so we refuse to touch anything until they're saved
+    if !force {
+        let head_listing = read_save_listing(&get_head());
+        let mut modified = Vec::new();
+        for header in selected.iter() {
+            let current_hash = match files::hash_file(&header.filename) {
+                Ok(hash) => hash,
+                Err(_) => continue,
+            };
+
+            if current_hash == header.content_hash() {
+                continue;
+            }
+
+            match head_listing.get(&header.filename) {
+                Some(head_header) if head_header.content_hash() == current_hash => {}
+                _ => modified.push(header.filename.clone()),
+            }
+        }
+
+        if !modified.is_empty() {
+            eprintln!("the following files have unsaved modifications:");
+            for file in modified.iter() {
+                eprintln!("  {}", display::red_string(file));
+            }
+
+            eprintln!("save them first or use --force to overwrite them");
+            std::process::exit(1);
+        }
+    }
+
+    // a file that can't be restored doesn't hold up the rest
+    let mut index = index::Index::read();
+    let mut failed = false;
+    for header in selected.iter() {
+        if let Err(e) = restore_file(header) {
+            eprintln!("failed to restore {}: {}", header.filename, e);
+            failed = true;
+            continue;
+        }
+
+        index.track(&header.filename);
+
+        println!("restored {}", header.filename);
+    }
+
+    index.write();
+    if failed {
+        eprintln!("run `recall verify` for a full report");
+        std::process::exit(1);
+    }
+}
+
+// every save something still leads to: the history, then the saves merged into it
+// and everything those descend from, which only merge parents point to
+//
+// a merged-in save that can't be read is still listed, for verify to report
+fn live_saves() -> Vec<String> {
+    check_merges_upgraded();
+
+    let history = read_history();
+    let parents = |hash: &str| {
+        let save_path = Path::new(".recall/commits").join(hash);
+        SaveReader::open(&save_path)
+            .map(|save| save.headers.parents())
+            .unwrap_or_default()
+    };
+
+    let mut live = history.iter().cloned().collect::<HashSet<String>>();
+    let mut pending = history
+        .iter()
+        .flat_map(|hash| parents(hash))
+        .collect::<Vec<String>>();
+
+    let mut merged = Vec::new();
+    while let Some(hash) = pending.pop() {
+        if !live.insert(hash.clone()) {
+            continue;
+        }
+
+        pending.extend(parents(&hash));
+        merged.push(hash);
+    }
+
+    // found newest first
+    merged.reverse();
+
+    history.into_iter().chain(merged).collect()
+}
+
+// the closest save that both saves descend from, if they have one
+fn merge_base(head: &str, other: &str) -> Option<String> {
+    check_merges_upgraded();
+
+    let parents = |hash: &str| open_save(hash).headers.parents();
+
+    let mut ancestors = HashSet::new();
+    let mut pending = vec![head.to_string()];
+    while let Some(hash) = pending.pop() {
+        if ancestors.insert(hash.clone()) {
+            pending.extend(parents(&hash));
+        }
+    }
+
+    // breadth first, so the nearest shared save is found first
+    let mut seen = HashSet::new();
+    let mut pending = std::collections::VecDeque::from([other.to_string()]);
+    while let Some(hash) = pending.pop_front() {
+        if ancestors.contains(&hash) {
+            return Some(hash);
+        }
+
+        if seen.insert(hash.clone()) {
+            pending.extend(parents(&hash));
+        }
+    }
+
+    None
+}
+
+// "lines 3-5" for a 0-based range of lines
+fn describe_lines(lines: &std::ops::Range<usize>) -> String {
+    match lines.len() {
+        0 => format!("nothing after line {}", lines.start),
+        1 => format!("line {}", lines.start + 1),
+        _ => format!("lines {}-{}", lines.start + 1, lines.end),
+    }
+}
+
+// a file the merge is going to write into the working tree
+struct MergedFile {
+    filename: String,
+    contents: Vec<u8>,
+    binary: bool,
+    // whether it merged cleanly, and so goes straight into the staged files
+    stage: bool,
+}
+
+// merges another save into the working tree, against the save it and the head last had in common
+//
+// files only the other save changed are taken as they are, and files both sides changed are
+// merged line by line. clean results are staged, while conflicts are left in the working tree
+// with markers around them for fixing up by hand
+//
+// the next save records that it merged the other save, so later merges start from there
+fn merge(args: Vec<String>) {
+    let usage = "usage: recall merge <hash>";
+
+    let theirs_hash = match args.as_slice() {
+        [hash] if !hash.starts_with('-') => match resolve_hash(hash) {
+            Ok(hash) => hash,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        _ => {
+            eprintln!("{}", usage);
+            return;
+        }
+    };
+
+    let head = match read_history().last() {
+        Some(head) => head.clone(),
+        None => {
+            eprintln!("there are no saves to merge into yet");
+            std::process::exit(1);
+        }
+    };
+
+    let index = index::Index::read();
+    if !index.staged().is_empty() || !index.removed().is_empty() {
+        eprintln!("there are staged files--save or unstage them before merging");
+        std::process::exit(1);
+    }
+
+    let base_hash = merge_base(&head, &theirs_hash);
+    if base_hash.as_deref() == Some(theirs_hash.as_str()) {
+        println!("already up to date");
+        return;
+    }
+
+    let base = match &base_hash {
+        Some(hash) => listing_versions(read_save_listing(hash)),
+        None => BTreeMap::new(),
+    };
+    let ours = listing_versions(read_save_listing(&head));
+    let theirs = listing_versions(read_save_listing(&theirs_hash));
+
+    let read = |filename: &str, hash: &str| match storage::read_object(hash) {
+        Ok(contents) => contents,
+        Err(e) => {
+            eprintln!("failed to read {}: {}", filename, e);
+            std::process::exit(1);
+        }
+    };
+
+    let short = &theirs_hash[..7];
+    let mut filenames = base
+        .keys()
+        .chain(ours.keys())
+        .chain(theirs.keys())
+        .collect::<Vec<&String>>();
+    filenames.sort();
+    filenames.dedup();
+
+    let mut writes = Vec::new();
+    // files only the other save deleted
+    let mut removals = Vec::new();
+    let mut messages = Vec::new();
+    let mut conflicted = false;
+    for filename in filenames {
+        let base_version = base.get(filename);
+        let ours_version = ours.get(filename);
+        let theirs_version = theirs.get(filename);
+        if ours_version == theirs_version || base_version == theirs_version {
+            continue;
+        }
+
+        if base_version == ours_version {
+            match theirs_version {
+                Some(version) => {
+                    let contents = read(filename, &version.hash);
+                    writes.push(MergedFile {
+                        filename: filename.clone(),
+                        binary: version.is_binary(&contents),
+                        contents,
+                        stage: true,
+                    });
+                    messages.push(format!("updated {}", filename));
+                }
+                None => {
+                    removals.push(filename.clone());
+                    messages.push(format!("deleted {}", filename));
+                }
+            }
+
+            continue;
+        }
+
+        // both sides changed it
+        let (ours_version, theirs_version) = match (ours_version, theirs_version) {
+            (Some(ours_version), Some(theirs_version)) => (ours_version, theirs_version),
+            (Some(_), None) => {
+                conflicted = true;
+                messages.push(format!(
+                    "CONFLICT (modify/delete): {} deleted in {}, kept the HEAD version",
+                    filename, short
+                ));
+                continue;
+            }
+            (None, Some(theirs_version)) => {
+                let contents = read(filename, &theirs_version.hash);
+                writes.push(MergedFile {
+                    filename: filename.clone(),
+                    binary: theirs_version.is_binary(&contents),
+                    contents,
+                    stage: false,
+                });
+                conflicted = true;
+                messages.push(format!(
+                    "CONFLICT (modify/delete): {} deleted in HEAD, wrote the {} version",
+                    filename, short
+                ));
+                continue;
+            }
+            (None, None) => unreachable!("both sides deleting a file isn't a change"),
+        };
+
+        let base_contents = base_version
+            .map(|v| read(filename, &v.hash))
+            .unwrap_or_default();
+        let ours_contents = read(filename, &ours_version.hash);
+        let theirs_contents = read(filename, &theirs_version.hash);
+        let binary = base_version.is_some_and(|v| v.is_binary(&base_contents))
+            || ours_version.is_binary(&ours_contents)
+            || theirs_version.is_binary(&theirs_contents);
+        let (base_text, ours_text, theirs_text) = match (
+            str::from_utf8(&base_contents),
+            str::from_utf8(&ours_contents),
+            str::from_utf8(&theirs_contents),
+        ) {
+            (Ok(base), Ok(ours), Ok(theirs)) if !binary => (base, ours, theirs),
+            _ => {
+                conflicted = true;
+                messages.push(format!(
+                    "CONFLICT (binary): {} changed on both sides, kept the HEAD version",
+                    filename
+                ));
+                continue;
+            }
+        };
+
+        let merged = merge::merge3(base_text, ours_text, theirs_text);
+        if !merged.is_clean() {
+            conflicted = true;
+            messages.push(format!("CONFLICT (content): {}", filename));
+            for conflict in merged.conflicts.iter() {
+                messages.push(format!(
+                    "  at line {}: base {}, HEAD {}, {} {}",
+                    conflict.line,
+                    describe_lines(&conflict.base),
+                    describe_lines(&conflict.ours),
+                    short,
+                    describe_lines(&conflict.theirs)
+                ));
+            }
+        } else {
+            messages.push(format!("merged {}", filename));
+        }
+
+        let contents = merged.text("HEAD", short).into_bytes();
+        writes.push(MergedFile {
+            filename: filename.clone(),
+            binary: files::is_binary(&contents),
+            contents,
+            stage: merged.is_clean(),
+        });
+    }
+
+    // like checkout, nothing gets overwritten that isn't in the head save
+    let mut modified = Vec::new();
+    let touched = writes.iter().map(|w| &w.filename).chain(removals.iter());
+    for filename in touched {
+        let current_hash = match files::hash_file(filename) {
+            Ok(hash) => hash,
+            Err(_) => continue,
+        };
+
+        if ours.get(filename).map(|v| &v.hash) != Some(&current_hash) {
+            modified.push(filename.clone());
+        }
+    }
+
+    if !modified.is_empty() {
+        eprintln!("the following files have unsaved modifications:");
+        for file in modified.iter() {
+            eprintln!("  {}", display::red_string(file));
+        }
+
+        eprintln!("save them first, then merge again");
+        std::process::exit(1);
+    }
+
+    let mut index = index;
+    let mut staged_files = Vec::new();
+    for write in writes {
+        let path = Path::new(&write.filename);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).expect("Failed to create directory");
+        }
+
+        std::fs::write(path, &write.contents).expect("Failed to write file");
+        index.track(&write.filename);
+
+        if write.stage {
+            let base = ours.get(&write.filename).map(|v| v.hash.as_str());
+            let hash = storage::write_object(&write.contents, base);
+            staged_files.push(files::StagedFile {
+                filename: write.filename,
+                hash,
+                binary: write.binary,
+            });
+        }
+    }
+
+    index.set_staged(staged_files);
+    for filename in removals {
+        remove_working_file(&filename);
+        index.remove(&filename, true);
+    }
+
+    index.write();
+    std::fs::write(".recall/merge_head", format!("{}\n", theirs_hash))
+        .expect("Failed to write file");
+
+    for message in messages.iter() {
+        println!("{}", message);
+    }
+
+    if conflicted {
+        println!("fix the conflicts, then stage the files and save the result");
+        std::process::exit(1);
+    }
+
+    println!("merged {} into the working tree--save to record it", short);
+}
+
+// where the contents of one side of a diff come from
+#[derive(Clone, Copy, PartialEq)]
+enum DiffSource {
+    ObjectStore,
+    WorkingTree,
+}
+
+// one side's copy of a file, and whether it's binary if that was recorded
+#[derive(Clone)]
+struct Version {
+    hash: String,
+    binary: Option<bool>,
+}
+
+// the same contents are the same version, whether or not the flag was recorded
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.hash == other.hash
+    }
+}
+
+impl Version {
+    fn of(header: &storage::FileHeaders) -> Self {
+        Version {
+            hash: header.content_hash(),
+            binary: header.binary,
+        }
+    }
+
+    // the contents only get looked at when the flag wasn't recorded
+    fn is_binary(&self, contents: &[u8]) -> bool {
+        self.binary.unwrap_or_else(|| files::is_binary(contents))
+    }
+}
+
+// filename -> version for one side of a diff
+fn listing_versions(listing: HashMap<String, storage::FileHeaders>) -> BTreeMap<String, Version> {
+    listing
+        .into_iter()
+        .map(|(filename, header)| {
+            let version = Version::of(&header);
+            (filename, version)
+        })
+        .collect()
+}
+
+// a file that differs between two sides of a diff,
+// with None standing in for a side that doesn't have it
+struct FileChange {
+    filename: String,
+    old: Option<Vec<u8>>,
+    new: Option<Vec<u8>>,
+    // the recorded binary flag for each side, None where the contents have to be looked at
+    old_binary: Option<bool>,
+    new_binary: Option<bool>,
+    // set when the old contents came from another path
+    origin: Option<Origin>,
+}
+
+// where a renamed or copied file came from
+struct Origin {
+    filename: String,
+    relation: renames::Relation,
+    similarity: usize,
+}
+
+impl FileChange {
+    fn old_is_binary(&self) -> bool {
+        let binary = self.old_binary;
+        self.old
+            .as_deref()
+            .is_some_and(|old| binary.unwrap_or_else(|| files::is_binary(old)))
+    }
+
+    fn new_is_binary(&self) -> bool {
+        let binary = self.new_binary;
+        self.new
+            .as_deref()
+            .is_some_and(|new| binary.unwrap_or_else(|| files::is_binary(new)))
+    }
+
+    // binary on either side means there's no text diff to show
+    fn is_binary(&self) -> bool {
+        self.old_is_binary() || self.new_is_binary()
+    }
+
+    // (old size, new size) in bytes
+    fn sizes(&self) -> (usize, usize) {
+        (
+            self.old.as_ref().map_or(0, |old| old.len()),
+            self.new.as_ref().map_or(0, |new| new.len()),
+        )
+    }
+}
+
+// folds added files that turn out to be moves or copies of removed or modified ones
+// into a single change from the old path, dropping the removals that were really renames
+//
+// `threshold` is the percent similar a pair has to be, with None turning detection off
+fn find_renames(changes: Vec<FileChange>, threshold: Option<usize>) -> Vec<FileChange> {
+    let threshold = match threshold {
+        Some(threshold) => threshold,
+        None => return changes,
+    };
+
+    let added = (0..changes.len())
+        .filter(|&i| changes[i].old.is_none())
+        .collect::<Vec<usize>>();
+    let candidates = (0..changes.len())
+        .filter(|&i| changes[i].old.is_some())
+        .collect::<Vec<usize>>();
+    if added.is_empty() || candidates.is_empty() {
+        return changes;
+    }
+
+    let sources = candidates
+        .iter()
+        .map(|&i| renames::Source {
+            contents: changes[i].old.as_deref().unwrap_or_default(),
+            binary: changes[i].old_is_binary(),
+            removed: changes[i].new.is_none(),
+        })
+        .collect::<Vec<renames::Source>>();
+    let targets = added
+        .iter()
+        .map(|&i| renames::Target {
+            contents: changes[i].new.as_deref().unwrap_or_default(),
+            binary: changes[i].new_is_binary(),
+        })
+        .collect::<Vec<renames::Target>>();
+
+    // change index -> (source change index, how it relates, similarity)
+    let mut origins = HashMap::new();
+    for pairing in renames::detect(&sources, &targets, threshold) {
+        origins.insert(
+            added[pairing.target],
+            (
+                candidates[pairing.source],
+                pairing.relation,
+                pairing.similarity,
+            ),
+        );
+    }
+
+    let renamed_away = origins
+        .values()
+        .filter(|(_, relation, _)| *relation == renames::Relation::Renamed)
+        .map(|(source, _, _)| *source)
+        .collect::<HashSet<usize>>();
+    let mut sources = origins
+        .iter()
+        .map(|(&target, &(source, _, _))| {
+            let filename = changes[source].filename.clone();
+            let old = changes[source].old.clone();
+            (target, (filename, old, changes[source].old_binary))
+        })
+        .collect::<HashMap<usize, (String, Option<Vec<u8>>, Option<bool>)>>();
+
+    let mut folded = Vec::new();
+    for (i, mut change) in changes.into_iter().enumerate() {
+        if renamed_away.contains(&i) {
+            continue;
+        }
+
+        if let Some((filename, old, old_binary)) = sources.remove(&i) {
+            let (_, relation, similarity) = origins[&i];
+            change.old = old;
+            change.old_binary = old_binary;
+            change.origin = Some(Origin {
+                filename,
+                relation,
+                similarity,
+            });
+        }
+
+        folded.push(change);
+    }
+
+    folded
+}
+
+// -M[<n>] / --find-renames[=<n>] set the similarity threshold, --no-renames turns detection off
+fn parse_rename_option(arg: &str, threshold: &mut Option<usize>) -> bool {
+    let value = match arg {
+        "--no-renames" => {
+            *threshold = None;
+            return true;
+        }
+        "-M" | "--find-renames" => "",
+        _ => match arg
+            .strip_prefix("-M")
+            .or_else(|| arg.strip_prefix("--find-renames="))
+        {
+            Some(value) => value,
+            None => return false,
+        },
+    };
+
+    if value.is_empty() {
+        *threshold = Some(renames::DEFAULT_THRESHOLD);
+        return true;
+    }
+
+    match value.trim_end_matches('%').parse::<usize>() {
+        Ok(percent) if percent <= 100 => {
+            *threshold = Some(percent);
+            true
+        }
+        _ => false,
+    }
+}
+
+// "old => new" for renames and copies
+fn change_name(change: &FileChange) -> String {
+    let filename = change.filename.trim_start_matches("./");
+    match &change.origin {
+        Some(origin) => format!(
+            "{} => {}",
+            origin.filename.trim_start_matches("./"),
+            filename
+        ),
+        None => filename.to_string(),
+    }
+}
+
+// past this, binary files are too big for a byte-level diff
+const HEX_DIFF_LIMIT: usize = 1024 * 1024;
+
+// loads the contents of every file whose hash differs between the two sides
+//
+// the old side always comes out of the object store
+fn changed_files(
+    old: &BTreeMap<String, Version>,
+    new: &BTreeMap<String, Version>,
+    new_source: DiffSource,
+    paths: &[String],
+) -> Vec<FileChange> {
+    let mut filenames = old.keys().chain(new.keys()).collect::<Vec<&String>>();
+    filenames.sort();
+    filenames.dedup();
+
+    let read = |filename: &str, version: Option<&Version>, source: DiffSource| match version {
+        Some(_) if source == DiffSource::WorkingTree => {
+            Some(std::fs::read(filename).expect("Failed to read file"))
+        }
+        Some(version) => match storage::read_object(&version.hash) {
+            Ok(contents) => Some(contents),
+            Err(e) => {
+                eprintln!("failed to read {}: {}", filename, e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let mut changes = Vec::new();
+    for filename in filenames {
+        if !matches_paths(filename, paths) {
+            continue;
+        }
+
+        let old_version = old.get(filename);
+        let new_version = new.get(filename);
+        if old_version == new_version {
+            continue;
+        }
+
+        changes.push(FileChange {
+            filename: filename.clone(),
+            old: read(filename, old_version, DiffSource::ObjectStore),
+            new: read(filename, new_version, new_source),
+            old_binary: old_version.and_then(|v| v.binary),
+            new_binary: new_version.and_then(|v| v.binary),
+            origin: None,
+        });
+    }
+
+    changes
+}
+
+// compares the working tree against the head save by default,
+// the staged files against the head save with --staged,
+// or one save against another when given two hashes
+//
+// --patch prints a unified diff that `patch` and friends can consume
+fn diff_command(args: Vec<String>) {
+    let usage = "usage: recall diff [--staged] [--patch [-U <lines>]] [--granularity <line|word|char>] [--hex] [-M[<n>] | --no-renames] [-w] [-b] [--ignore-blank-lines] [--ignore-cr-at-eol] [hash] [hash] [files...]";
+
+    let mut options = diff::Options::default();
+    let mut rename_threshold = Some(renames::DEFAULT_THRESHOLD);
+    let mut hex = false;
+    let mut staged = false;
+    let mut patch = false;
+    let mut context_lines = DEFAULT_CONTEXT_LINES;
+    let mut positional = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--staged" | "--cached" => staged = true,
+            "--patch" | "-p" => patch = true,
+            "--hex" => hex = true,
+            "--granularity" => match args.next().as_deref() {
+                Some("line") => options.granularity = diff::Granularity::Line,
+                Some("word") => options.granularity = diff::Granularity::Word,
+                Some("char") => options.granularity = diff::Granularity::Char,
+                _ => {
+                    eprintln!("{}", usage);
+                    return;
+                }
+            },
+            "-U" | "--unified" => match args.next().and_then(|n| n.parse::<usize>().ok()) {
+                Some(n) => {
+                    patch = true;
+                    context_lines = n;
+                }
+                None => {
+                    eprintln!("{}", usage);
+                    return;
+                }
+            },
+            _ if parse_ignore_option(&arg, &mut options) => {}
+            _ if parse_rename_option(&arg, &mut rename_threshold) => {}
+            _ if arg.starts_with('-') => {
+                eprintln!("unknown option: {}", arg);
+                eprintln!("{}", usage);
+                return;
+            }
+            _ => positional.push(arg),
+        }
+    }
+
+    // leading arguments name saves unless they're paths on disk,
+    // or tracked files that have been deleted from it
+    let mut saves = Vec::new();
+    while saves.len() < std::cmp::min(2, positional.len()) {
+        let arg = &positional[saves.len()];
+        if Path::new(arg).exists() {
+            break;
+        }
+
+        match resolve_hash(arg) {
+            Ok(hash) => saves.push(hash),
+            Err(e) => {
+                let path = vec![files::normalize_filename(
+                    arg.trim_end_matches('/').to_string(),
+                )];
+                let mut known = index::Index::read().tracked();
+                known.extend(read_head_listing().into_keys());
+                if known.iter().any(|f| matches_paths(f, &path)) {
+                    break;
+                }
+
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if staged && !saves.is_empty() {
+        eprintln!("--staged can't be combined with save hashes");
+        eprintln!("{}", usage);
+        return;
+    }
+
+    let paths = positional[saves.len()..]
+        .iter()
+        .map(|p| files::normalize_filename(p.trim_end_matches('/').to_string()))
+        .collect::<Vec<String>>();
+
+    let old = match saves.first() {
+        Some(hash) => listing_versions(read_save_listing(hash)),
+        None => listing_versions(read_head_listing()),
+    };
+
+    let (new, new_source) = if saves.len() == 2 {
+        (
+            listing_versions(read_save_listing(&saves[1])),
+            DiffSource::ObjectStore,
+        )
+    } else if staged {
+        let index = index::Index::read();
+        let mut new = old.clone();
+        for staged_file in index.staged() {
+            let version = Version {
+                hash: staged_file.hash,
+                binary: Some(staged_file.binary),
+            };
+            new.insert(staged_file.filename, version);
+        }
+
+        for filename in index.removed() {
+            new.remove(&filename);
+        }
+
+        (new, DiffSource::ObjectStore)
+    } else {
+        let mut new = BTreeMap::new();
+        let mut candidates = index::Index::read().tracked();
+        candidates.extend(old.keys().cloned());
+        for filename in candidates {
+            if !matches_paths(&filename, &paths) {
+                continue;
+            }
+
+            // the working tree has no flag, so its contents get looked at
+            if let Ok(hash) = files::hash_file(&filename) {
+                new.insert(filename, Version { hash, binary: None });
+            }
+        }
+
+        (new, DiffSource::WorkingTree)
+    };
+
+    // patches keep renames as a removal and an addition, which any patch tool can apply
+    let mut changes = changed_files(&old, &new, new_source, &paths);
+    if !patch {
+        changes = find_renames(changes, rename_threshold);
+    }
+
+    for change in changes {
+        if patch {
+            print!("{}", unified_file_diff(&change, context_lines, &options));
+        } else {
+            print_file_diff(change, &options, hex);
+        }
+    }
+}
+
+// the whitespace and line ending options shared by diff, log and status
+fn parse_ignore_option(arg: &str, options: &mut diff::Options) -> bool {
+    match arg {
+        "-w" | "--ignore-all-space" => options.ignore_all_space = true,
+        "-b" | "--ignore-space-change" => options.ignore_space_change = true,
+        "--ignore-blank-lines" => options.ignore_blank_lines = true,
+        "--ignore-cr-at-eol" => options.ignore_cr_at_eol = true,
+        _ => return false,
+    }
+
+    true
+}
+
+// binary files get a one line summary, or a hex dump of the changed bytes with `hex`
+fn print_file_diff(change: FileChange, options: &diff::Options, hex: bool) {
+    let header = match (&change.origin, &change.old, &change.new) {
+        (Some(origin), _, _) => format!(
+            "diff {} → {} ({}, {}% similar)",
+            origin.filename,
+            change.filename,
+            match origin.relation {
+                renames::Relation::Renamed => "renamed",
+                renames::Relation::Copied => "copied",
+            },
+            origin.similarity
+        ),
+        (None, None, _) => format!("diff {} (new file)", change.filename),
+        (None, _, None) => format!("diff {} (deleted)", change.filename),
+        _ => format!("diff {}", change.filename),
+    };
+
+    let both_exist = change.old.is_some() && change.new.is_some();
+    let body = if change.is_binary() {
+        let (old_size, new_size) = change.sizes();
+        let summary = format!(
+            "Binary files differ ({} → {})\n",
+            format_size(old_size),
+            format_size(new_size)
+        );
+
+        let old = change.old.unwrap_or_default();
+        let new = change.new.unwrap_or_default();
+        if !hex {
+            summary
+        } else if old.len() > HEX_DIFF_LIMIT || new.len() > HEX_DIFF_LIMIT {
+            summary + "(too large for a hex diff)\n"
+        } else {
+            diff::hex_diff(&old, &new)
+        }
+    } else {
+        // not binary, so both sides are valid UTF-8
+        let old = String::from_utf8(change.old.unwrap_or_default()).unwrap_or_default();
+        let new = String::from_utf8(change.new.unwrap_or_default()).unwrap_or_default();
+        let file_diff = diff::diff(old, new, options);
+        // nothing left to show once the ignored changes are taken out,
+        // though a file that moved without changing still says so
+        if both_exist && file_diff.is_unchanged() {
+            if change.origin.is_none() {
+                return;
+            }
+
+            String::new()
+        } else {
+            file_diff.to_pretty_string()
+        }
+    };
+
+    println!("{}", display::yellow_string(&header));
+    print!("{}", body);
+    println!();
+}
+
+// a/ and b/ prefixed names, the way `patch -p1` expects them
+fn patch_names(change: &FileChange) -> (String, String) {
+    let filename = change.filename.trim_start_matches("./");
+    let old_name = match change.old {
+        Some(_) => format!("a/{}", filename),
+        None => String::from("/dev/null"),
+    };
+
+    let new_name = match change.new {
+        Some(_) => format!("b/{}", filename),
+        None => String::from("/dev/null"),
+    };
+
+    (old_name, new_name)
+}
+
+fn unified_file_diff(change: &FileChange, context_lines: usize, options: &diff::Options) -> String {
+    let (old_name, new_name) = patch_names(change);
+    let old = std::str::from_utf8(change.old.as_deref().unwrap_or_default());
+    let new = std::str::from_utf8(change.new.as_deref().unwrap_or_default());
+    match (old, new) {
+        (Ok(old), Ok(new)) if !change.is_binary() => {
+            let mut file_diff =
+                diff::diff(old.to_string(), new.to_string(), &line_options(options));
+            file_diff.source_name = old_name;
+            file_diff.changed_name = new_name;
+
+            file_diff.to_unified(context_lines)
+        }
+        _ => format!("Binary files {} and {} differ\n", old_name, new_name),
+    }
+}
+
+// patches and stats only care about whole lines
+fn line_options(options: &diff::Options) -> diff::Options {
+    diff::Options {
+        granularity: diff::Granularity::Line,
+        ..*options
+    }
+}
+
+// (lines added, lines removed) for a file, None if it isn't text
+fn line_stats(change: &FileChange, options: &diff::Options) -> Option<(usize, usize)> {
+    if change.is_binary() {
+        return None;
+    }
+
+    let old = std::str::from_utf8(change.old.as_deref().unwrap_or_default()).ok()?;
+    let new = std::str::from_utf8(change.new.as_deref().unwrap_or_default()).ok()?;
+
+    Some(diff::diff(old.to_string(), new.to_string(), &line_options(options)).line_stats())
+}
+
+// a `file | 12 +++---` line per file, then the totals
+//
+// renames and copies are listed under the stat, with how similar each pair was
+fn print_diffstat(changes: &[FileChange], options: &diff::Options) {
+    let width = changes
+        .iter()
+        .map(|c| change_name(c).chars().count())
+        .max()
+        .unwrap_or(0);
+    let (mut insertions, mut deletions) = (0, 0);
+    for change in changes.iter() {
+        let filename = change_name(change);
+        match line_stats(change, options) {
+            Some((added, removed)) => {
+                insertions += added;
+                deletions += removed;
+                // big changes get their +/- bar scaled down to fit
+                let changed = added + removed;
+                let (bar_added, bar_removed) = if changed > MAX_STAT_BAR {
+                    let bar_added = added * MAX_STAT_BAR / changed;
+                    (bar_added, MAX_STAT_BAR - bar_added)
+                } else {
+                    (added, removed)
+                };
+
+                println!(
+                    " {:width$} | {} {}{}",
+                    filename,
+                    changed,
+                    "+".repeat(bar_added),
+                    "-".repeat(bar_removed),
+                    width = width
+                );
+            }
+            None => {
+                let (old_size, new_size) = change.sizes();
+                println!(
+                    " {:width$} | Bin {} → {}",
+                    filename,
+                    format_size(old_size),
+                    format_size(new_size),
+                    width = width
+                );
+            }
+        }
+    }
+
+    println!(
+        " {} files changed, {} insertions(+), {} deletions(-)",
+        changes.len(),
+        insertions,
+        deletions
+    );
+
+    for change in changes.iter() {
+        if let Some(origin) = &change.origin {
+            let relation = match origin.relation {
+                renames::Relation::Renamed => "rename",
+                renames::Relation::Copied => "copy",
+            };
+            println!(
+                " {} {} ({}%)",
+                relation,
+                change_name(change),
+                origin.similarity
+            );
+        }
+    }
+}
+
+// prints a save as an email-style patch against its parent:
+// the memo as the subject and body, a diffstat, then the unified diff
+fn format_patch(args: Vec<String>) {
+    let usage = "usage: recall format-patch [-U <lines>] <hash>";
+
+    let mut context_lines = DEFAULT_CONTEXT_LINES;
+    let mut hash = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-U" | "--unified" => match args.next().and_then(|n| n.parse::<usize>().ok()) {
+                Some(n) => context_lines = n,
+                None => {
+                    eprintln!("{}", usage);
+                    return;
+                }
+            },
+            _ if hash.is_none() && !arg.starts_with('-') => hash = Some(arg),
+            _ => {
+                eprintln!("{}", usage);
+                return;
+            }
+        }
+    }
+
+    let hash = match hash.map(|h| resolve_hash(&h)) {
+        Some(Ok(hash)) => hash,
+        Some(Err(e)) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        None => {
+            eprintln!("{}", usage);
+            return;
+        }
+    };
+
+    let headers = open_save(&hash).headers;
+    let parent = headers.parent();
+    let old = match &parent {
+        Some(parent) => listing_versions(read_save_listing(parent)),
+        None => BTreeMap::new(),
+    };
+
+    let new = listing_versions(read_save_listing(&hash));
+    let changes = changed_files(&old, &new, DiffSource::ObjectStore, &[]);
+
+    let mut memo_lines = headers.memo.lines();
+    let subject = memo_lines.next().unwrap_or_default();
+    let body = memo_lines.collect::<Vec<&str>>().join("\n");
+
+    println!("From {} Mon Sep 17 00:00:00 2001", hash);
+    println!("From: {}", storage::from_byte_slice(&headers.creator));
+    println!("Date: {}", format_date(headers.created_date));
+    println!("Subject: [PATCH] {}", subject);
+    println!();
+    if !body.trim().is_empty() {
+        println!("{}", body.trim());
+        println!();
+    }
+
+    println!("---");
+
+    let options = diff::Options::default();
+    print_diffstat(&changes, &options);
+    println!();
+
+    for change in changes.iter() {
+        print!("{}", unified_file_diff(change, context_lines, &options));
+    }
+}
+
+// applies a unified diff to the working tree, or with --cached to the staged files
+//
+// hunks that can't be placed in the working tree are written to <file>.rej
+// for fixing up by hand, while --cached only goes through if everything applies
+//
+// --check reports what would happen without touching anything
+fn apply(args: Vec<String>) {
+    let usage = "usage: recall apply [--check] [--cached] [-p <n>] [--fuzz <n>] <patch>";
+
+    let mut check = false;
+    let mut cached = false;
+    let mut strip = 1;
+    let mut fuzz = DEFAULT_FUZZ;
+    let mut patch_path = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--check" => check = true,
+            "--cached" | "--staged" => cached = true,
+            "-p" | "--fuzz" => match args.next().and_then(|n| n.parse::<usize>().ok()) {
+                Some(n) if arg == "-p" => strip = n,
+                Some(n) => fuzz = n,
+                None => {
+                    eprintln!("{}", usage);
+                    return;
+                }
+            },
+            _ if patch_path.is_none() && !arg.starts_with('-') => patch_path = Some(arg),
+            _ => {
+                eprintln!("{}", usage);
+                return;
+            }
+        }
+    }
+
+    let patch_path = match patch_path {
+        Some(path) => path,
+        None => {
+            eprintln!("{}", usage);
+            return;
+        }
+    };
+
+    let text = match std::fs::read_to_string(&patch_path) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("failed to read {}: {}", patch_path, e);
+            std::process::exit(1);
+        }
+    };
+
+    let file_patches = match patch::parse(&text, strip) {
+        Ok(file_patches) => file_patches,
+        Err(e) => {
+            eprintln!("{}: {}", patch_path, e);
+            std::process::exit(1);
+        }
+    };
+
+    if file_patches.is_empty() {
+        eprintln!("{}: no changes found", patch_path);
+        std::process::exit(1);
+    }
+
+    let head_listing = read_head_listing();
+    let mut index = index::Index::read();
+    let mut staged_files = index.staged();
+
+    // the patched contents of every file, held back until everything's been
+    // tried since --cached is all or nothing
+    let mut results: Vec<(String, Option<String>)> = Vec::new();
+    let mut failed = false;
+    for file_patch in file_patches.iter() {
+        let filename = match file_patch.path() {
+            Some(path) => files::normalize_filename(path.clone()),
+            None => continue,
+        };
+
+        if file_patch.binary {
+            eprintln!("{}: binary patches aren't supported", filename);
+            failed = true;
+            continue;
+        }
+
+        // the staged version of the file, falling back to what's in the head save
+        let staged_hash = staged_files
+            .iter()
+            .find(|f| f.filename == filename)
+            .map(|f| f.hash.clone())
+            .or_else(|| head_listing.get(&filename).map(|h| h.content_hash()));
+
+        let original = if cached {
+            match staged_hash.map(|hash| storage::read_object(&hash)) {
+                Some(Ok(contents)) => Some(contents),
+                Some(Err(e)) => {
+                    eprintln!("failed to read {}: {}", filename, e);
+                    std::process::exit(1);
+                }
+                None => None,
+            }
+        } else {
+            std::fs::read(&filename).ok()
+        };
+
+        let original = match (original, &file_patch.old_path) {
+            (Some(contents), Some(_)) => contents,
+            (Some(contents), None) if contents.is_empty() => contents,
+            (Some(_), None) => {
+                eprintln!("{}: already exists", filename);
+                failed = true;
+                continue;
+            }
+            (None, Some(_)) => {
+                eprintln!("{}: no such file", filename);
+                failed = true;
+                continue;
+            }
+            (None, None) => Vec::new(),
+        };
+
+        let original = match String::from_utf8(original) {
+            Ok(original) => original,
+            Err(_) => {
+                eprintln!("{}: can't patch a binary file", filename);
+                failed = true;
+                continue;
+            }
+        };
+
+        println!("patching {}", filename);
+        let (patched, hunk_results) = patch::apply(&original, &file_patch.hunks, fuzz);
+
+        let mut rejected = Vec::new();
+        for (i, (hunk, result)) in file_patch.hunks.iter().zip(hunk_results).enumerate() {
+            match result {
+                patch::HunkResult::Applied { line, offset, fuzz } if offset != 0 || fuzz != 0 => {
+                    let mut note = format!("Hunk #{} succeeded at {}", i + 1, line);
+                    if fuzz != 0 {
+                        note += &format!(" with fuzz {}", fuzz);
+                    }
+
+                    if offset != 0 {
+                        let plural = if offset.abs() == 1 { "" } else { "s" };
+                        note += &format!(" (offset {} line{})", offset, plural);
+                    }
+
+                    println!("{}", note);
+                }
+                patch::HunkResult::Applied { .. } => {}
+                patch::HunkResult::Rejected => {
+                    println!("Hunk #{} FAILED at {}", i + 1, hunk.source_start);
+                    rejected.push(hunk);
+                }
+            }
+        }
+
+        if !rejected.is_empty() {
+            failed = true;
+
+            // rejects only make sense next to a working copy
+            if !check && !cached {
+                let rej_path = format!("{}.rej", filename);
+                let mut rej = format!(
+                    "--- {}\n+++ {}\n",
+                    file_patch.old_path.as_deref().unwrap_or("/dev/null"),
+                    file_patch.new_path.as_deref().unwrap_or("/dev/null")
+                );
+
+                for hunk in rejected.iter() {
+                    rej.push_str(&hunk.to_unified());
+                }
+
+                std::fs::write(&rej_path, rej).expect("Failed to write rejects");
+                println!(
+                    "{} out of {} hunks FAILED -- saving rejects to {}",
+                    rejected.len(),
+                    file_patch.hunks.len(),
+                    rej_path
+                );
+            }
+        }
+
+        // a deleted file is expected to end up empty
+        let patched = match &file_patch.new_path {
+            None if patched.is_empty() => None,
+            None => {
+                eprintln!(
+                    "{}: not empty after deleting, leaving it in place",
+                    filename
+                );
+                failed = true;
+                Some(patched)
+            }
+            Some(_) => Some(patched),
+        };
+
+        results.push((filename, patched));
+    }
+
+    if check || (cached && failed) {
+        if failed {
+            eprintln!("patch does not apply");
+            std::process::exit(1);
+        }
+
+        return;
+    }
+
+    let mut removals = Vec::new();
+    for (filename, patched) in results {
+        match patched {
+            Some(patched) if cached => {
+                let base = head_listing.get(&filename).map(|h| h.content_hash());
+                let hash = storage::write_object(&patched.into_bytes(), base.as_deref());
+
+                // patches only ever produce text
+                match staged_files.iter_mut().find(|f| f.filename == filename) {
+                    Some(staged_file) => {
+                        staged_file.hash = hash;
+                        staged_file.binary = false;
+                    }
+                    None => staged_files.push(files::StagedFile {
+                        filename,
+                        hash,
+                        binary: false,
+                    }),
+                }
+            }
+            Some(patched) => {
+                if let Some(parent) = Path::new(&filename).parent() {
+                    std::fs::create_dir_all(parent).expect("Failed to create directory");
+                }
+
+                std::fs::write(&filename, patched).expect("Failed to write file");
+            }
+            None if cached => {
+                staged_files.retain(|f| f.filename != filename);
+                removals.push(filename);
+            }
+            None => std::fs::remove_file(&filename).expect("Failed to remove file"),
+        }
+    }
+
+    if cached {
+        index.set_staged(staged_files);
+        for filename in removals {
+            let saved = head_listing.contains_key(&filename);
+            index.remove(&filename, saved);
+        }
+
+        index.write();
+    }
+
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+// checks that every save in the history, or merged into it, decodes, is internally
+// consistent, and that every object it refers to is present and intact
+//
+// exits non-zero if anything's wrong
+fn verify(args: Vec<String>) {
+    let mut check_hashes = false;
+    for arg in args.iter() {
+        match arg.as_str() {
+            "--hashes" => check_hashes = true,
+            _ => {
+                eprintln!("unknown option: {}", arg);
+                eprintln!("usage: recall verify [--hashes]");
+                return;
+            }
+        }
+    }
+
+    // report sections, in the order they're printed
+    const MISSING_SAVE: &str = "missing save files";
+    const CORRUPT_SAVE: &str = "corrupt saves";
+    const NEEDS_UPGRADE: &str = "saves needing `recall upgrade`";
+    const BAD_SAVE_HASH: &str = "save hash mismatches";
+    const BAD_PARENT: &str = "broken parent links";
+    const BAD_OBJECT: &str = "missing or corrupt objects";
+    const BAD_LENGTH: &str = "content length mismatches";
+    const BAD_CONTENT_HASH: &str = "content hash mismatches";
+    const ORPHANED: &str = "orphaned save files";
+
+    let mut problems: Vec<(&str, String)> = Vec::new();
+
+    let saves = live_saves();
+    let mut checked_objects: HashMap<String, Option<usize>> = HashMap::new();
+    for hash in saves.iter() {
+        let short = short_hash(hash);
+        let contents = match std::fs::read(Path::new(".recall/commits").join(hash)) {
+            Ok(contents) => contents,
+            Err(_) => {
+                problems.push((MISSING_SAVE, hash.clone()));
+                continue;
+            }
+        };
+
+        let save = match Save::from_bytes(&contents) {
+            Ok(save) => save,
+            Err(e) => {
+                problems.push((CORRUPT_SAVE, format!("{}: {}", hash, e)));
+                continue;
+            }
+        };
+
+        // hashed differently, and with contents that aren't in the object store yet
+        if storage::read_version(&contents, &mut 0).ok() == Some(storage::LEGACY_VERSION) {
+            problems.push((NEEDS_UPGRADE, hash.clone()));
+            continue;
+        }
+
+        let stored_hash = storage::from_byte_slice(&save.headers.hash);
+        let computed_hash = save.compute_hash();
+        if stored_hash != *hash || computed_hash != *hash {
+            problems.push((
+                BAD_SAVE_HASH,
+                format!(
+                    "{}: stored as {}, recomputed as {}",
+                    hash, stored_hash, computed_hash
+                ),
+            ));
+        }
+
+        if let Some(parent) = save.headers.parent() {
+            if !saves.contains(&parent) {
+                problems.push((
+                    BAD_PARENT,
+                    format!("{}: parent {} is not a known save", short, parent),
+                ));
+            }
+        }
+
+        for merged in save.headers.merged() {
+            if !saves.contains(&merged) {
+                problems.push((
+                    BAD_PARENT,
+                    format!("{}: merged save {} is not a known save", short, merged),
+                ));
+            }
+        }
+
+        for header in save.blob.headers.iter() {
+            let object = header.content_hash();
+            let length = match checked_objects.get(&object) {
+                Some(length) => *length,
+                None => {
+                    let contents = match storage::read_object(&object) {
+                        Ok(contents) => contents,
+                        Err(e) => {
+                            problems.push((
+                                BAD_OBJECT,
+                                format!("{} {}: {}", short, header.filename, e),
+                            ));
+                            checked_objects.insert(object, None);
+                            continue;
+                        }
+                    };
+
+                    if check_hashes && files::get_hash(&contents) != object {
+                        problems.push((
+                            BAD_CONTENT_HASH,
+                            format!(
+                                "{} {}: object {} hashes to {}",
+                                short,
+                                header.filename,
+                                object,
+                                files::get_hash(&contents)
+                            ),
+                        ));
+                    }
+
+                    checked_objects.insert(object, Some(contents.len()));
+                    Some(contents.len())
+                }
+            };
+
+            if let Some(length) = length {
+                if length != header.content_length {
+                    problems.push((
+                        BAD_LENGTH,
+                        format!(
+                            "{} {}: header says {} bytes, object has {}",
+                            short, header.filename, header.content_length, length
+                        ),
+                    ));
+                }
+            }
+        }
+    }
+
+    for entry in std::fs::read_dir(".recall/commits").expect("Failed to read directory") {
+        let name = entry
+            .expect("Failed to read entry")
+            .file_name()
+            .to_string_lossy()
+            .to_string();
+
+        if !saves.contains(&name) {
+            problems.push((ORPHANED, name));
+        }
+    }
+
+    println!(
+        "checked {} saves, {} objects",
+        saves.len(),
+        checked_objects.len()
+    );
+
+    if problems.is_empty() {
+        println!("no problems found");
+        return;
+    }
+
+    for section in [
+        MISSING_SAVE,
+        CORRUPT_SAVE,
+        NEEDS_UPGRADE,
+        BAD_SAVE_HASH,
+        BAD_PARENT,
+        BAD_OBJECT,
+        BAD_LENGTH,
+        BAD_CONTENT_HASH,
+        ORPHANED,
+    ] {
+        let entries = problems
+            .iter()
+            .filter(|p| p.0 == section)
+            .collect::<Vec<&(&str, String)>>();
+
+        if entries.is_empty() {
+            continue;
+        }
+
+        println!();
+        println!("{} ({}):", section, entries.len());
+        for (_, problem) in entries {
+            println!("  {}", display::red_string(problem));
+        }
+    }
+
+    println!();
+    println!("{} problems found", problems.len());
+    std::process::exit(1);
+}
+
+// rewrites any saves from older format versions in the current one
+//
+// version 0 saves only held the files staged for them, inline, and had no parent link.
+// upgrading one moves its contents into the object store, carries the rest of the tree
+// forward from the save before it, and links it to that save. saves from before version 3
+// kept their merges in .recall/merges, which get folded into their headers
+//
+// either changes a save's hash, and so the hash of every save after it
+fn upgrade() {
+    let history = read_history();
+
+    // save -> the one before it in the history
+    let previous = history
+        .windows(2)
+        .map(|pair| (pair[1].clone(), pair[0].clone()))
+        .collect::<HashMap<String, String>>();
+
+    // "save merged" pairs, one per line
+    let mut side_merges: HashMap<String, Vec<String>> = HashMap::new();
+    let merges_contents = std::fs::read_to_string(".recall/merges").unwrap_or_default();
+    for line in merges_contents.lines() {
+        if let Some((save, merged)) = line.split_once(' ') {
+            side_merges
+                .entry(save.to_string())
+                .or_default()
+                .push(merged.to_string());
+        }
+    }
+
+    // every save, each after the saves it descends from, so a parent's new hash
+    // is known by the time its children are rewritten
+    let mut order = Vec::new();
+    let mut visited = HashSet::new();
+    let mut pending = history
+        .iter()
+        .rev()
+        .map(|hash| (hash.clone(), false))
+        .collect::<Vec<(String, bool)>>();
+    while let Some((hash, expanded)) = pending.pop() {
+        if expanded {
+            order.push(hash);
+            continue;
+        }
+
+        let save_path = Path::new(".recall/commits").join(&hash);
+        if !save_path.exists() || !visited.insert(hash.clone()) {
+            continue;
+        }
+
+        pending.push((hash.clone(), true));
+
+        let mut parents = SaveReader::open(&save_path)
+            .map(|save| save.headers.parents())
+            .unwrap_or_default();
+        parents.extend(side_merges.get(&hash).cloned().unwrap_or_default());
+        parents.extend(previous.get(&hash).cloned());
+        for parent in parents {
+            if !visited.contains(&parent) {
+                pending.push((parent, false));
+            }
+        }
+    }
+
+    let mut renamed: HashMap<String, String> = HashMap::new();
+    let mut upgraded = 0;
+    for hash in order {
+        let save_path = Path::new(".recall/commits").join(&hash);
+        let contents = std::fs::read(&save_path).expect("Failed to read file");
+        let version = match storage::read_version(&contents, &mut 0) {
+            Ok(version) => version,
+            Err(e) => report_corruption(&hash, e),
+        };
+
+        let rename = |hash: String| renamed.get(&hash).cloned().unwrap_or(hash);
+        let merged = side_merges.remove(&hash).unwrap_or_default();
+
+        let mut save = if version == storage::LEGACY_VERSION {
+            let (mut save, inline) = match Save::from_legacy_bytes(&contents) {
+                Ok(decoded) => decoded,
+                Err(e) => report_corruption(&hash, e),
+            };
+
+            // already written out under its new hash, if it needed one
+            let parent = previous.get(&hash).cloned().map(rename);
+
+            let mut carried = parent
+                .as_ref()
+                .map(|p| read_save_listing(p))
+                .unwrap_or_default();
+            for (header, datum) in save.blob.headers.iter_mut().zip(inline.iter()) {
+                let base = carried.remove(&header.filename).map(|h| h.content_hash());
+                storage::write_object(datum, base.as_deref());
+                header.binary = Some(files::is_binary(datum));
+            }
+
+            save.blob.headers.extend(carried.into_values());
+            save.blob
+                .headers
+                .sort_by(|a, b| a.filename.cmp(&b.filename));
+            let parent = parent.unwrap_or_default();
+            save.headers.parent = to_byte_slice!(parent.as_bytes(), HASH_LENGTH);
+
+            save
+        } else {
+            let mut save = read_save(&hash);
+            let unchanged = save
+                .headers
+                .parents()
+                .iter()
+                .all(|p| !renamed.contains_key(p));
+            if version == storage::FORMAT_VERSION && unchanged && merged.is_empty() {
+                continue;
+            }
+
+            if let Some(parent) = save.headers.parent().map(rename) {
+                save.headers.parent = to_byte_slice!(parent.as_bytes(), HASH_LENGTH);
+            }
+
+            let merged = save.headers.merged().into_iter().map(rename);
+            save.headers.merged = merged
+                .map(|m| to_byte_slice!(m.as_bytes(), HASH_LENGTH))
+                .collect();
+
+            // older saves didn't record which files are binary
+            for header in save.blob.headers.iter_mut() {
+                if header.binary.is_some() {
+                    continue;
+                }
+
+                match storage::read_object(&header.content_hash()) {
+                    Ok(contents) => header.binary = Some(files::is_binary(&contents)),
+                    Err(e) => report_corruption(&hash, e),
+                }
+            }
+
+            save
+        };
+
+        for merged in merged.into_iter().map(rename) {
+            let merged = to_byte_slice!(merged.as_bytes(), HASH_LENGTH);
+            if !save.headers.merged.contains(&merged) {
+                save.headers.merged.push(merged);
+            }
+        }
+
+        let new_hash = save.compute_hash();
+        save.headers.hash = to_byte_slice!(new_hash.as_bytes(), HASH_LENGTH);
+
+        // write alongside and swap in so an interrupted upgrade can't lose the save
+        let new_path = Path::new(".recall/commits").join(&new_hash);
+        let temp_path = new_path.with_extension("tmp");
+        std::fs::write(&temp_path, save.to_bytes()).expect("Failed to write to file");
+        std::fs::rename(&temp_path, &new_path).expect("Failed to write to file");
+
+        if new_hash == hash {
+            println!(
+                "upgraded {} from version {} to {}",
+                short_hash(&hash),
+                version,
+                storage::FORMAT_VERSION
+            );
+        } else {
+            println!(
+                "upgraded {} from version {} to {}, now {}",
+                short_hash(&hash),
+                version,
+                storage::FORMAT_VERSION,
+                short_hash(&new_hash)
+            );
+            renamed.insert(hash, new_hash);
+        }
+
+        upgraded += 1;
+    }
+
+    // the old saves stay in place until nothing refers to them
+    for path in [".recall/history", ".recall/merge_head"] {
+        if renamed.is_empty() {
+            break;
+        }
+
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(_) => continue,
+        };
+
+        let mut rewritten = contents
+            .lines()
+            .map(|hash| renamed.get(hash).map(|h| h.as_str()).unwrap_or(hash))
+            .collect::<Vec<&str>>()
+            .join("\n");
+        rewritten.push('\n');
+
+        let temp_path = format!("{}.tmp", path);
+        std::fs::write(&temp_path, rewritten).expect("Failed to write to file");
+        std::fs::rename(&temp_path, path).expect("Failed to write to file");
+    }
+
+    for old in renamed.keys() {
+        std::fs::remove_file(Path::new(".recall/commits").join(old))
+            .expect("Failed to remove file");
+    }
+
+    // everything in it is in the save headers now
+    if Path::new(".recall/merges").exists() {
+        std::fs::remove_file(".recall/merges").expect("Failed to remove file");
+    }
+
+    println!("{} saves upgraded", upgraded);
+}
+
+// drops objects no save refers to, optionally rebuilding every delta chain
+fn gc(args: Vec<String>) {
+    let mut repack = false;
+    for arg in args.iter() {
+        match arg.as_str() {
+            "--repack" => repack = true,
+            _ => {
+                eprintln!("unknown option: {}", arg);
+                eprintln!("usage: recall gc [--repack]");
+                return;
+            }
+        }
+    }
+
+    // each path's distinct versions, oldest first
+    let mut paths: HashMap<String, usize> = HashMap::new();
+    let mut versions: Vec<Vec<String>> = Vec::new();
+    let mut add_version = |filename: &String, object: String| match paths.get(filename) {
+        Some(&index) => {
+            if versions[index].last() != Some(&object) {
+                versions[index].push(object);
+            }
+        }
+        None => {
+            paths.insert(filename.clone(), versions.len());
+            versions.push(vec![object]);
+        }
+    };
+
+    for hash in live_saves() {
+        let save = read_save(&hash);
+        for header in save.blob.headers.iter() {
+            add_version(&header.filename, header.content_hash());
+        }
+    }
+
+    // staged contents aren't part of any save yet, but they still need keeping
+    for staged_file in index::Index::read().staged() {
+        add_version(&staged_file.filename, staged_file.hash);
+    }
+
+    if repack {
+        match storage::repack(&versions) {
+            Ok(count) => println!("repacked {} objects", count),
+            Err(e) => {
+                eprintln!("failed to repack: {}", e);
+                eprintln!("run `recall verify` for a full report");
+                std::process::exit(1);
+            }
+        }
+    } else {
+        let reachable = versions.concat();
+        let removed = storage::collect_garbage(&reachable);
+        println!("removed {} unreachable objects", removed);
+    }
+}
+
+// says whether each path is ignored, and which rule decided it
+//
+// exits 1 when none of them are, like grep does when nothing matches
+fn check_ignore(args: Vec<String>) {
+    let usage = "usage: recall check-ignore <paths...>";
+    if args.is_empty() || args.iter().any(|arg| arg.starts_with('-')) {
+        eprintln!("{}", usage);
+        std::process::exit(1);
+    }
+
+    let mut any_ignored = false;
+    for path in args.iter() {
+        let is_dir = ignore::is_directory(path);
+        let rules = ignore::Ignore::for_path(path);
+        match rules.explain(path, is_dir) {
+            Some(ignore::Reason::BuiltIn(name)) => {
+                any_ignored = true;
+                println!("{}\tignored: everything under {} always is", path, name);
+            }
+            Some(ignore::Reason::Rule(rule)) if !rule.negated => {
+                any_ignored = true;
+                println!(
+                    "{}\tignored by {}:{}:{}",
+                    path, rule.source, rule.line, rule.pattern
+                );
+            }
+            Some(ignore::Reason::Rule(rule)) => println!(
+                "{}\tnot ignored, brought back by {}:{}:{}",
+                path, rule.source, rule.line, rule.pattern
+            ),
+            None => println!("{}\tnot ignored", path),
+        }
+    }
+
+    if !any_ignored {
+        std::process::exit(1);
+    }
+}
+
+// with any of the whitespace options, files whose only changes are
+// ones those options ignore aren't reported as changed
+//
+// tracked files that have gone missing are matched up against new files,
+// so a file that was moved shows up as a rename
+fn status(args: Vec<String>) {
+    let usage = "usage: recall status [--jobs <n>] [-M[<n>] | --no-renames] [-w] [-b] [--ignore-blank-lines] [--ignore-cr-at-eol]";
+
+    let mut options = diff::Options::default();
+    let mut rename_threshold = Some(renames::DEFAULT_THRESHOLD);
+    let mut jobs = parallel::default_jobs();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-j" | "--jobs" => match parse_jobs(args.next()) {
+                Some(n) => jobs = n,
+                None => {
+                    eprintln!("{}", usage);
+                    return;
+                }
+            },
+            _ if parse_ignore_option(&arg, &mut options) => {}
+            _ if parse_rename_option(&arg, &mut rename_threshold) => {}
+            _ => {
+                eprintln!("unknown option: {}", arg);
+                eprintln!("{}", usage);
+                return;
+            }
+        }
+    }
+
+    let all_unignored_files = files::get_unignored_files(jobs);
+    let mut index = index::Index::read();
+    let tracked_files = index.tracked();
+    let tracked_set = tracked_files.iter().collect::<HashSet<&String>>();
+    let untracked_files: Vec<String> = all_unignored_files
+        .iter()
+        .filter(|f| !tracked_set.contains(f))
+        .map(|f| f.clone())
+        .collect();
+    let staged_files = index.staged();
+
+    // before the first save, everything tracked is new
+    let head_listing = read_head_listing();
+    let mut tracked_changed_files = Vec::new();
+    // tracked since the head save, but not staged
+    let mut new_files = Vec::new();
+    let mut missing_files = Vec::new();
+    // files untouched since they were last hashed keep their cached hash
+    let hashes = index.hash_files(&tracked_files, jobs);
+    for (tracked_file, file_hash) in tracked_files.into_iter().zip(hashes) {
+        let file_hash = match file_hash {
+            Ok(hash) => hash,
+            Err(_) => {
+                // what it last had, for working out whether it moved
+                let last_version = staged_files
+                    .iter()
+                    .find(|f| f.filename == tracked_file)
+                    .map(|f| Version {
+                        hash: f.hash.clone(),
+                        binary: Some(f.binary),
+                    })
+                    .or_else(|| head_listing.get(&tracked_file).map(Version::of));
+                missing_files.push((tracked_file.clone(), last_version));
+
+                continue;
+            }
+        };
+
+        match head_listing.get(&tracked_file) {
+            Some(head_header) => {
+                if file_hash != head_header.content_hash()
+                    && !(options.ignores_anything()
+                        && only_ignored_changes(
+                            &tracked_file,
+                            &head_header.content_hash(),
+                            &options,
+                        ))
+                {
+                    tracked_changed_files.push(tracked_file.clone());
+                }
+            }
+            None => {
+                if !staged_files.iter().any(|f| f.filename == tracked_file) {
+                    new_files.push(tracked_file.clone());
+                }
+            }
+        }
+    }
+
+    index.write_if_changed();
+
+    // a missing or removed file is a removal, and anything new is an addition--staged or not
+    let removed_files = index.removed();
+    let mut moves = Vec::new();
+    if !missing_files.is_empty() || !removed_files.is_empty() {
+        let removals = missing_files.iter().cloned().chain(
+            removed_files
+                .iter()
+                .map(|f| (f.clone(), head_listing.get(f).map(Version::of))),
+        );
+
+        let mut changes = Vec::new();
+        for (filename, version) in removals {
+            changes.push(FileChange {
+                old: version
+                    .as_ref()
+                    .and_then(|v| storage::read_object(&v.hash).ok()),
+                old_binary: version.and_then(|v| v.binary),
+                filename,
+                new: None,
+                new_binary: None,
+                origin: None,
+            });
+        }
+
+        let mut additions = untracked_files.clone();
+        additions.extend(
+            staged_files
+                .iter()
+                .filter(|f| !head_listing.contains_key(&f.filename))
+                .map(|f| f.filename.clone()),
+        );
+        additions.sort();
+        additions.dedup();
+        // a file removed with --cached is still there, but it didn't move anywhere
+        additions.retain(|f| !removed_files.contains(f));
+        for filename in additions.iter() {
+            if let Ok(contents) = std::fs::read(filename) {
+                changes.push(FileChange {
+                    filename: filename.clone(),
+                    old: None,
+                    new: Some(contents),
+                    old_binary: None,
+                    new_binary: None,
+                    origin: None,
+                });
+            }
+        }
+
+        moves = find_renames(changes, rename_threshold)
+            .into_iter()
+            .filter_map(|change| change.origin.map(|origin| (origin, change.filename)))
+            .collect::<Vec<(Origin, String)>>();
+    }
+
+    let renamed = |filename: &str| {
+        moves.iter().any(|(origin, _)| {
+            origin.filename == filename && origin.relation == renames::Relation::Renamed
+        })
+    };
+
+    // where a file was moved to is listed with its rename instead
+    let is_new = |filename: &String| {
+        !head_listing.contains_key(filename) && !moves.iter().any(|(_, moved)| moved == filename)
+    };
+
+    if !staged_files.is_empty() || !removed_files.is_empty() {
+        println!("Staged files:");
+        for staged_file in staged_files.iter() {
+            let filename = &staged_file.filename;
+            if is_new(filename) {
+                println!(
+                    "  {}",
+                    display::green_string(&format!("new file: {}", filename))
+                );
+            } else {
+                println!("  {}", display::green_string(filename));
+            }
+        }
+
+        for filename in removed_files.iter().filter(|f| !renamed(f)) {
+            println!(
+                "  {}",
+                display::green_string(&format!("deleted: {}", filename))
+            );
+        }
+    }
+
+    if !tracked_changed_files.is_empty() {
+        for tracked_file in tracked_changed_files.iter() {
+            if staged_files.iter().any(|f| f.filename == *tracked_file) {
+                continue;
+            }
+
+            println!("  {}", display::green_string(&tracked_file));
+        }
+    }
+
+    for filename in new_files.iter().filter(|f| is_new(f)) {
+        println!(
+            "  {}",
+            display::red_string(&format!("new file: {}", filename))
+        );
+    }
+
+    for (filename, _) in missing_files.iter() {
+        if !renamed(filename) {
+            println!(
+                "  {}",
+                display::red_string(&format!("deleted: {}", filename))
+            );
+        }
+    }
+
+    if !moves.is_empty() {
+        println!();
+        println!("Renamed files:");
+        for (origin, filename) in moves.iter() {
+            let relation = match origin.relation {
+                renames::Relation::Renamed => "",
+                renames::Relation::Copied => "copy, ",
+            };
+            println!(
+                "  {}",
+                display::green_string(&format!(
+                    "{} -> {} ({}{}% similar)",
+                    origin.filename, filename, relation, origin.similarity
+                ))
+            );
+        }
+    }
+
+    println!();
+
+    println!("Untracked files:");
+    for untracked_file in untracked_files.iter() {
+        if staged_files.iter().any(|f| f.filename == *untracked_file) {
+            continue;
+        }
+
+        if moves.iter().any(|(_, filename)| filename == untracked_file) {
+            continue;
+        }
+
+        println!("  {}", display::red_string(&untracked_file));
+    }
+}
+
+// whether a working file differs from the saved object only in ways `options` ignores
+fn only_ignored_changes(filename: &str, hash: &str, options: &diff::Options) -> bool {
+    let old = storage::read_object(hash)
+        .ok()
+        .and_then(|o| String::from_utf8(o).ok());
+    let new = std::fs::read(filename)
+        .ok()
+        .and_then(|n| String::from_utf8(n).ok());
+    match (old, new) {
+        (Some(old), Some(new)) => diff::diff(old, new, &line_options(options)).is_unchanged(),
+        _ => false,
+    }
+}
+
+fn save(args: Vec<String>) {
+    let usage = "usage: recall save [--jobs <n>] [memo]";
+
+    let mut memo = None;
+    let mut jobs = parallel::default_jobs();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-j" | "--jobs" => match parse_jobs(args.next()) {
+                Some(n) => jobs = n,
+                None => {
+                    eprintln!("{}", usage);
+                    return;
+                }
+            },
+            _ if memo.is_none() => memo = Some(arg),
+            _ => {
+                eprintln!("{}", usage);
+                return;
+            }
+        }
+    }
+
+    let memo = match memo {
+        Some(memo) => memo,
+        None => {
+            eprintln!("{}", usage);
+            return;
+        }
+    };
+
+    let mut index = index::Index::read();
+    let staged_files = index.staged();
+
+    // everything that wasn't restaged is carried forward from the parent,
+    // so each save is a complete snapshot of the tracked tree
+    let parent = read_history().last().cloned();
+    let parent_save = parent.as_ref().map(|p| read_save(p));
+    let removed_files = index.removed();
+    let blob = match storage::blobify(
+        staged_files,
+        &removed_files,
+        parent_save.as_ref().map(|s| &s.blob),
+        jobs,
+    ) {
+        Ok(blob) => blob,
+        Err(e) => {
+            eprintln!("failed to save staged files: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("Failed to get time")
+        .as_micros();
+
+    // finishing a merge
+    let merged = std::fs::read_to_string(".recall/merge_head")
+        .map(|merged| vec![to_byte_slice!(merged.trim().as_bytes(), HASH_LENGTH)])
+        .unwrap_or_default();
+
+    let parent = parent.unwrap_or_default();
+    let memo_size = memo.len();
+    println!("memo size: {}", memo_size);
+    let headers = SaveHeaders {
+        hash: [0; HASH_LENGTH],
+        parent: to_byte_slice!(parent.as_bytes(), HASH_LENGTH),
+        memo,
+        memo_size,
+        created_date: now,
+        creator: to_byte_slice!("recall".as_bytes(), CREATOR_LENGTH),
+        merged,
+    };
+
+    let mut save = Save { headers, blob };
+    let hash = save.compute_hash();
+    save.headers.hash = to_byte_slice!(hash.as_bytes(), HASH_LENGTH);
+
+    // write save bytes to file named with the hash
+    let save_bytes = save.to_bytes();
+    let save_path = Path::new(".recall/commits").join(hash.clone());
+    let mut save_file = std::fs::File::create(save_path).expect("Failed to create file");
+    save_file
+        .write_all(&save_bytes)
+        .expect("Failed to write to file");
+
+    index.clear_staged();
+    index.write();
+
+    if !save.headers.merged.is_empty() {
+        std::fs::remove_file(".recall/merge_head").expect("Failed to remove file");
+    }
+
+    let mut history_file = std::fs::OpenOptions::new()
+        .write(true)
+        .append(true)
+        .open(".recall/history")
+        .expect("Failed to open file");
+    history_file
+        .write_all(format!("{}\n", hash).as_bytes())
+        .expect("Failed to write to file");
+}
+
+// this is just a testing function
+fn print_commit() {
+    let commit_path = Path::new(".recall/commits");
+    let commit_files = std::fs::read_dir(commit_path).expect("Failed to read directory");
+    for entry in commit_files {
+        let entry = entry.expect("Failed to read entry");
+        let path = entry.path();
+
+        println!("commit: {}", path.display());
+        let mut file = std::fs::File::open(path).expect("Failed to open file");
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)
+            .expect("Failed to read file");
+
+        let save = match Save::from_bytes(&contents) {
+            Ok(save) => save,
+            Err(e) => {
+                println!("  <corrupt: {}>", e);
+                continue;
+            }
+        };
+
+        println!("hash: {:?}", save.headers.hash);
+        println!("memo: {}", save.headers.memo);
+        println!("created: {:?}", save.headers.created_date);
+        println!("creator: {:?}", save.headers.creator);
+        println!("files:");
+        for header in save.blob.headers.iter() {
+            println!("  {:?}", header.filename);
+            println!(
+                "  object, length: {}, {}",
+                header.content_hash(),
+                header.content_length
+            );
+
+            let content_bytes = match save.blob.get_file(&header.filename) {
+                Ok(content_bytes) => content_bytes.unwrap(),
+                Err(e) => {
+                    println!("    <{}>", e);
+                    continue;
+                }
+            };
+
+            // saves from before binary files were flagged still need checking by hand
+            if header
+                .binary
+                .unwrap_or_else(|| files::is_binary(&content_bytes))
+            {
+                println!("    <binary>");
+            } else {
+                println!("    {}", String::from_utf8_lossy(&content_bytes));
+            }
+        }
     }
 }
 
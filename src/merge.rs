@@ -0,0 +1,246 @@
+// three-way merging of text, diff3 style
+//
+// both sides are diffed line by line against their common base, and the base
+// lines that neither side touched split the files into stable runs and the
+// changed chunks between them. a chunk only one side changed takes that side's
+// lines, and a chunk both sides changed differently becomes a conflict
+
+use std::ops::Range;
+
+use crate::diff;
+
+// a chunk both sides changed, as line ranges into each of the inputs
+pub struct Conflict {
+    pub base: Range<usize>,
+    pub ours: Range<usize>,
+    pub theirs: Range<usize>,
+    // the 1-based line of the merged text its `<<<<<<<` marker is on
+    pub line: usize,
+}
+
+enum Piece {
+    Merged(String),
+    Conflict { ours: String, theirs: String },
+}
+
+pub struct Merge {
+    pieces: Vec<Piece>,
+    pub conflicts: Vec<Conflict>,
+}
+
+impl Merge {
+    pub fn is_clean(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+
+    // the merged text, with each conflict wrapped in markers
+    // naming the side its lines came from
+    pub fn text(&self, ours_label: &str, theirs_label: &str) -> String {
+        // markers go on lines of their own, even after a line missing its newline
+        fn push_marker(text: &mut String, marker: &str, label: &str) {
+            if !text.is_empty() && !text.ends_with('\n') {
+                text.push('\n');
+            }
+
+            text.push_str(marker);
+            if !label.is_empty() {
+                text.push(' ');
+                text.push_str(label);
+            }
+
+            text.push('\n');
+        }
+
+        let mut text = String::new();
+        for piece in self.pieces.iter() {
+            match piece {
+                Piece::Merged(lines) => text.push_str(lines),
+                Piece::Conflict { ours, theirs } => {
+                    push_marker(&mut text, "<<<<<<<", ours_label);
+                    text.push_str(ours);
+                    push_marker(&mut text, "=======", "");
+                    text.push_str(theirs);
+                    push_marker(&mut text, ">>>>>>>", theirs_label);
+                }
+            }
+        }
+
+        text
+    }
+}
+
+// the base line index -> matched line index on the other side
+fn matches(base: &[&str], other: &[&str]) -> Vec<Option<usize>> {
+    let mut matched = vec![None; base.len()];
+    for pair in diff::lcs(base, other) {
+        matched[pair.first] = Some(pair.second);
+    }
+
+    matched
+}
+
+pub fn merge3(base: &str, ours: &str, theirs: &str) -> Merge {
+    let base = base.split_inclusive('\n').collect::<Vec<&str>>();
+    let ours = ours.split_inclusive('\n').collect::<Vec<&str>>();
+    let theirs = theirs.split_inclusive('\n').collect::<Vec<&str>>();
+
+    let ours_matches = matches(&base, &ours);
+    let theirs_matches = matches(&base, &theirs);
+
+    let mut pieces = Vec::new();
+    let mut conflicts = Vec::new();
+    // lines of merged text so far, counting the three markers around each conflict
+    let mut line_count = 0;
+
+    let (mut b, mut o, mut t) = (0, 0, 0);
+    loop {
+        // base lines both sides kept, in the same place, go straight through
+        let stable = b;
+        while b < base.len() && ours_matches[b] == Some(o) && theirs_matches[b] == Some(t) {
+            b += 1;
+            o += 1;
+            t += 1;
+        }
+
+        if b > stable {
+            pieces.push(Piece::Merged(base[stable..b].concat()));
+            line_count += b - stable;
+        }
+
+        if b == base.len() && o == ours.len() && t == theirs.len() {
+            break;
+        }
+
+        // the chunk runs up to the next base line both sides kept
+        let next =
+            (b..base.len()).find(|&k| ours_matches[k].is_some() && theirs_matches[k].is_some());
+        let (b_end, o_end, t_end) = match next {
+            Some(k) => (k, ours_matches[k].unwrap(), theirs_matches[k].unwrap()),
+            None => (base.len(), ours.len(), theirs.len()),
+        };
+
+        let base_chunk = &base[b..b_end];
+        let ours_chunk = &ours[o..o_end];
+        let theirs_chunk = &theirs[t..t_end];
+        if ours_chunk == base_chunk || ours_chunk == theirs_chunk {
+            pieces.push(Piece::Merged(theirs_chunk.concat()));
+            line_count += theirs_chunk.len();
+        } else if theirs_chunk == base_chunk {
+            pieces.push(Piece::Merged(ours_chunk.concat()));
+            line_count += ours_chunk.len();
+        } else {
+            conflicts.push(Conflict {
+                base: b..b_end,
+                ours: o..o_end,
+                theirs: t..t_end,
+                line: line_count + 1,
+            });
+            pieces.push(Piece::Conflict {
+                ours: ours_chunk.concat(),
+                theirs: theirs_chunk.concat(),
+            });
+            line_count += ours_chunk.len() + theirs_chunk.len() + 3;
+        }
+
+        b = b_end;
+        o = o_end;
+        t = t_end;
+    }
+
+    Merge { pieces, conflicts }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merged(base: &str, ours: &str, theirs: &str) -> String {
+        let merge = merge3(base, ours, theirs);
+        assert!(merge.is_clean());
+
+        merge.text("ours", "theirs")
+    }
+
+    #[test]
+    fn a_change_on_one_side_is_taken() {
+        let base = "a\nb\nc\n";
+
+        assert_eq!(merged(base, "a\nB\nc\n", base), "a\nB\nc\n");
+        assert_eq!(merged(base, base, "a\nb\nC\n"), "a\nb\nC\n");
+
+        // separate changes on each side don't get in each other's way
+        assert_eq!(
+            merged("a\nb\nc\nd\n", "a\nB\nc\nd\n", "a\nb\nc\nD\n"),
+            "a\nB\nc\nD\n"
+        );
+    }
+
+    #[test]
+    fn the_same_change_on_both_sides_is_taken_once() {
+        assert_eq!(
+            merged("a\nb\nc\n", "a\nB\nc\nd\n", "a\nB\nc\nd\n"),
+            "a\nB\nc\nd\n"
+        );
+    }
+
+    #[test]
+    fn adjacent_edits_conflict() {
+        let merge = merge3("a\nb\nc\nd\n", "a\nB\nc\nd\n", "a\nb\nC\nd\n");
+
+        assert_eq!(merge.conflicts.len(), 1);
+        assert_eq!(merge.conflicts[0].base, 1..3);
+        assert_eq!(
+            merge.text("ours", "theirs"),
+            "a\n<<<<<<< ours\nB\nc\n=======\nb\nC\n>>>>>>> theirs\nd\n"
+        );
+    }
+
+    #[test]
+    fn overlapping_edits_conflict() {
+        let merge = merge3("a\nb\nc\n", "a\nours\nc\n", "a\ntheirs\nc\n");
+
+        assert_eq!(merge.conflicts.len(), 1);
+        let conflict = &merge.conflicts[0];
+        assert_eq!(conflict.base, 1..2);
+        assert_eq!(conflict.ours, 1..2);
+        assert_eq!(conflict.theirs, 1..2);
+        assert_eq!(
+            merge.text("ours", "theirs"),
+            "a\n<<<<<<< ours\nours\n=======\ntheirs\n>>>>>>> theirs\nc\n"
+        );
+    }
+
+    #[test]
+    fn insertions_at_end_of_file_without_a_newline() {
+        let base = "a\nb";
+
+        assert_eq!(merged(base, "a\nb\nc", base), "a\nb\nc");
+        assert_eq!(merged(base, base, "a\nb\nc"), "a\nb\nc");
+
+        // the markers still end up on lines of their own
+        let merge = merge3(base, "a\nb\nc", "a\nb\nd");
+        assert_eq!(merge.conflicts.len(), 1);
+        assert_eq!(
+            merge.text("ours", "theirs"),
+            "a\n<<<<<<< ours\nb\nc\n=======\nb\nd\n>>>>>>> theirs\n"
+        );
+    }
+
+    #[test]
+    fn conflict_lines_point_at_their_markers() {
+        let merge = merge3(
+            "a\nb\nc\nd\ne\n",
+            "a\nb1\nc\nd1\nd2\ne\n",
+            "a\nb2\nc\nd3\ne\n",
+        );
+        let text = merge.text("ours", "theirs");
+        let lines = text.lines().collect::<Vec<&str>>();
+
+        assert_eq!(merge.conflicts.len(), 2);
+        assert_eq!(merge.conflicts[0].line, 2);
+        assert_eq!(merge.conflicts[1].line, 8);
+        for conflict in merge.conflicts.iter() {
+            assert_eq!(lines[conflict.line - 1], "<<<<<<< ours");
+        }
+    }
+}
@@ -0,0 +1,49 @@
+// spreading work over a handful of threads
+//
+// results always come back in the order the work went in, however it got split up,
+// so anything built from them-- file listings, saves-- is the same from run to run
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+// one thread per core, unless told otherwise with --jobs
+pub fn default_jobs() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
+
+// `f` applied to every item on up to `jobs` threads, with the results in item order
+pub fn map<T: Sync, R: Send>(items: &[T], jobs: usize, f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    let jobs = jobs.clamp(1, std::cmp::max(items.len(), 1));
+    if jobs == 1 {
+        return items.iter().map(f).collect();
+    }
+
+    // threads pull the next item as they free up, rather than each taking a fixed share,
+    // since one big file shouldn't hold up everything after it
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::with_capacity(items.len()));
+    std::thread::scope(|scope| {
+        for _ in 0..jobs {
+            scope.spawn(|| {
+                let mut done = Vec::new();
+                loop {
+                    let k = next.fetch_add(1, Ordering::Relaxed);
+                    if k >= items.len() {
+                        break;
+                    }
+
+                    done.push((k, f(&items[k])));
+                }
+
+                results.lock().unwrap().extend(done);
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(k, _)| *k);
+
+    results.into_iter().map(|(_, result)| result).collect()
+}
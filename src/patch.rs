@@ -0,0 +1,545 @@
+// reading unified diffs (as written by `Diff::to_unified`, git, or plain diff -u)
+// and applying them to file contents
+//
+// lines keep their trailing newline throughout, so a line that's missing one
+// (the `\ No newline at end of file` case) is only ever matched by another that's missing one
+
+pub enum HunkLine {
+    Context(String),
+    Removed(String),
+    Added(String),
+}
+
+pub struct Hunk {
+    pub source_start: usize,
+    pub source_len: usize,
+    pub changed_start: usize,
+    pub changed_len: usize,
+    pub lines: Vec<HunkLine>,
+}
+
+impl Hunk {
+    fn source_lines(&self) -> Vec<&str> {
+        self.lines
+            .iter()
+            .filter_map(|line| match line {
+                HunkLine::Context(text) | HunkLine::Removed(text) => Some(text.as_str()),
+                HunkLine::Added(_) => None,
+            })
+            .collect()
+    }
+
+    fn changed_lines(&self) -> Vec<&str> {
+        self.lines
+            .iter()
+            .filter_map(|line| match line {
+                HunkLine::Context(text) | HunkLine::Added(text) => Some(text.as_str()),
+                HunkLine::Removed(_) => None,
+            })
+            .collect()
+    }
+
+    // how many lines of context lead and trail the hunk's changes
+    fn context_lengths(&self) -> (usize, usize) {
+        let is_context = |line: &&HunkLine| matches!(line, HunkLine::Context(_));
+        let leading = self.lines.iter().take_while(is_context).count();
+        let trailing = self.lines.iter().rev().take_while(is_context).count();
+
+        (leading, trailing)
+    }
+
+    pub fn to_unified(&self) -> String {
+        let mut unified = format!(
+            "@@ -{},{} +{},{} @@\n",
+            self.source_start, self.source_len, self.changed_start, self.changed_len
+        );
+
+        for line in self.lines.iter() {
+            let (marker, text) = match line {
+                HunkLine::Context(text) => (' ', text),
+                HunkLine::Removed(text) => ('-', text),
+                HunkLine::Added(text) => ('+', text),
+            };
+
+            unified.push(marker);
+            unified.push_str(text);
+            if !text.ends_with('\n') {
+                unified.push_str("\n\\ No newline at end of file\n");
+            }
+        }
+
+        unified
+    }
+}
+
+// the changes to a single file, with None standing in for /dev/null
+// on the side of a file that's being created or deleted
+pub struct FilePatch {
+    pub old_path: Option<String>,
+    pub new_path: Option<String>,
+    pub hunks: Vec<Hunk>,
+    pub binary: bool,
+}
+
+impl FilePatch {
+    // the path the patch applies to
+    pub fn path(&self) -> Option<&String> {
+        self.new_path.as_ref().or(self.old_path.as_ref())
+    }
+}
+
+// drops `strip` leading components from a patch path, the way `patch -p` does
+fn strip_path(path: &str, strip: usize) -> Option<String> {
+    // anything after a tab is a timestamp
+    let path = path.split('\t').next().unwrap_or_default().trim_end();
+    if path == "/dev/null" {
+        return None;
+    }
+
+    let stripped = path.splitn(strip + 1, '/').nth(strip).unwrap_or(path);
+
+    Some(stripped.to_string())
+}
+
+// patch paths get written to, so they have to stay inside the repository
+fn check_path(path: Option<String>, line: usize) -> Result<Option<String>, String> {
+    let escapes = path.as_deref().is_some_and(|path| {
+        std::path::Path::new(path).components().any(|c| {
+            !matches!(
+                c,
+                std::path::Component::Normal(_) | std::path::Component::CurDir
+            )
+        })
+    });
+
+    if escapes {
+        return Err(format!(
+            "line {}: path outside the repository: {}",
+            line,
+            path.unwrap_or_default()
+        ));
+    }
+
+    Ok(path)
+}
+
+// "-12,3" -> (12, 3), with the length defaulting to 1 when left off
+fn parse_range(range: &str) -> Option<(usize, usize)> {
+    let mut parts = range.splitn(2, ',');
+    let start = parts.next()?.parse().ok()?;
+    let length = match parts.next() {
+        Some(length) => length.parse().ok()?,
+        None => 1,
+    };
+
+    Some((start, length))
+}
+
+fn parse_hunk_header(line: &str) -> Option<Hunk> {
+    let mut parts = line.strip_prefix("@@ ")?.split(' ');
+    let (source_start, source_len) = parse_range(parts.next()?.strip_prefix('-')?)?;
+    let (changed_start, changed_len) = parse_range(parts.next()?.strip_prefix('+')?)?;
+
+    Some(Hunk {
+        source_start,
+        source_len,
+        changed_start,
+        changed_len,
+        lines: Vec::new(),
+    })
+}
+
+// anything outside of a file's ---/+++ headers and hunks
+// (email headers, diffstats, `diff --git` lines) is skipped over
+pub fn parse(text: &str, strip: usize) -> Result<Vec<FilePatch>, String> {
+    let lines = text.split_inclusive('\n').collect::<Vec<&str>>();
+
+    let mut patches: Vec<FilePatch> = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        i += 1;
+
+        if line.starts_with("Binary files ") {
+            // "Binary files a/x and b/y differ"
+            let names = line
+                .trim_end()
+                .trim_start_matches("Binary files ")
+                .trim_end_matches(" differ");
+            if let Some((old, new)) = names.split_once(" and ") {
+                patches.push(FilePatch {
+                    old_path: check_path(strip_path(old, strip), i)?,
+                    new_path: check_path(strip_path(new, strip), i)?,
+                    hunks: Vec::new(),
+                    binary: true,
+                });
+            }
+
+            continue;
+        }
+
+        if let Some(old) = line.strip_prefix("--- ") {
+            let new = match lines.get(i).and_then(|l| l.strip_prefix("+++ ")) {
+                Some(new) => new,
+                None => continue,
+            };
+
+            i += 1;
+            patches.push(FilePatch {
+                old_path: check_path(strip_path(old.trim_end_matches('\n'), strip), i - 1)?,
+                new_path: check_path(strip_path(new.trim_end_matches('\n'), strip), i)?,
+                hunks: Vec::new(),
+                binary: false,
+            });
+
+            continue;
+        }
+
+        if !line.starts_with("@@ ") {
+            continue;
+        }
+
+        let patch = match patches.last_mut() {
+            Some(patch) => patch,
+            None => return Err(format!("line {}: hunk without a file header", i)),
+        };
+
+        let mut hunk = match parse_hunk_header(line) {
+            Some(hunk) => hunk,
+            None => return Err(format!("line {}: malformed hunk header", i)),
+        };
+
+        let mut source_seen = 0;
+        let mut changed_seen = 0;
+        while source_seen < hunk.source_len || changed_seen < hunk.changed_len {
+            let line = match lines.get(i) {
+                Some(line) => *line,
+                None => return Err(format!("line {}: patch ends in the middle of a hunk", i)),
+            };
+
+            i += 1;
+
+            // some editors strip the space off of empty context lines
+            let (marker, text) = match line {
+                "\n" => (" ", "\n"),
+                _ => line.split_at(line.chars().next().map_or(0, |c| c.len_utf8())),
+            };
+
+            match marker {
+                " " => {
+                    hunk.lines.push(HunkLine::Context(text.to_string()));
+                    source_seen += 1;
+                    changed_seen += 1;
+                }
+                "-" => {
+                    hunk.lines.push(HunkLine::Removed(text.to_string()));
+                    source_seen += 1;
+                }
+                "+" => {
+                    hunk.lines.push(HunkLine::Added(text.to_string()));
+                    changed_seen += 1;
+                }
+                "\\" => {}
+                _ => return Err(format!("line {}: unexpected line in hunk", i)),
+            }
+
+            // the marker applies to whichever line came right before it
+            if lines.get(i).is_some_and(|l| l.starts_with('\\')) {
+                i += 1;
+                if let Some(
+                    HunkLine::Context(text) | HunkLine::Removed(text) | HunkLine::Added(text),
+                ) = hunk.lines.last_mut()
+                {
+                    if text.ends_with('\n') {
+                        text.pop();
+                    }
+                }
+            }
+        }
+
+        if source_seen != hunk.source_len || changed_seen != hunk.changed_len {
+            return Err(format!("line {}: hunk doesn't match its header", i));
+        }
+
+        patch.hunks.push(hunk);
+    }
+
+    Ok(patches)
+}
+
+// where and how a hunk ended up applying
+pub enum HunkResult {
+    // the 1-based line it landed on, how far that was from where the
+    // patch said it'd be, and how many lines of context had to be ignored
+    Applied {
+        line: usize,
+        offset: isize,
+        fuzz: usize,
+    },
+    Rejected,
+}
+
+// applies each hunk where its context matches, looking outward from where the
+// patch says it should go and ignoring up to `max_fuzz` lines of context at
+// either end when an exact match can't be found
+//
+// hunks that can't be placed are left out of the result
+pub fn apply(contents: &str, hunks: &[Hunk], max_fuzz: usize) -> (String, Vec<HunkResult>) {
+    let mut lines = contents
+        .split_inclusive('\n')
+        .map(|l| l.to_string())
+        .collect::<Vec<String>>();
+
+    let mut results = Vec::new();
+
+    // how far lines have moved from their place in the original,
+    // from the hunks applied so far and from wherever those were found
+    let mut shift: isize = 0;
+    // hunks can't overlap the ones before them
+    let mut floor = 0;
+    for hunk in hunks.iter() {
+        let source = hunk.source_lines();
+        let changed = hunk.changed_lines();
+        let (leading, trailing) = hunk.context_lengths();
+
+        // an empty source range is numbered by the line before it
+        let start = if hunk.source_len == 0 {
+            hunk.source_start
+        } else {
+            hunk.source_start.saturating_sub(1)
+        };
+        let expected = start as isize + shift;
+
+        let mut found = None;
+        for fuzz in 0..=max_fuzz {
+            let lead = std::cmp::min(fuzz, leading);
+            let trail = std::cmp::min(fuzz, trailing);
+            if fuzz > 0 && lead + trail == 0 {
+                break;
+            }
+
+            // fuzzing away every line of context would match anywhere
+            if lead + trail >= source.len() && !source.is_empty() {
+                break;
+            }
+
+            let needle = &source[lead..source.len() - trail];
+            if let Some(position) = find(&lines, needle, expected + lead as isize, floor) {
+                found = Some((position, lead, trail, fuzz));
+                break;
+            }
+
+            if leading <= fuzz && trailing <= fuzz {
+                break;
+            }
+        }
+
+        match found {
+            Some((position, lead, trail, fuzz)) => {
+                let replacement = changed[lead..changed.len() - trail]
+                    .iter()
+                    .map(|l| l.to_string())
+                    .collect::<Vec<String>>();
+                let removed = source.len() - lead - trail;
+                let added = replacement.len();
+                lines.splice(position..position + removed, replacement);
+
+                let actual = position as isize - lead as isize;
+                shift = actual - start as isize + added as isize - removed as isize;
+                floor = position + added;
+
+                results.push(HunkResult::Applied {
+                    line: (actual + 1) as usize,
+                    offset: actual - expected,
+                    fuzz,
+                });
+            }
+            None => results.push(HunkResult::Rejected),
+        }
+    }
+
+    (lines.concat(), results)
+}
+
+// the index nearest `expected` (and no earlier than `floor`) where `needle` starts
+fn find(lines: &[String], needle: &[&str], expected: isize, floor: usize) -> Option<usize> {
+    if needle.len() > lines.len() {
+        return None;
+    }
+
+    let last = (lines.len() - needle.len()) as isize;
+    let expected = expected.clamp(floor as isize, std::cmp::max(last, floor as isize));
+    let matches = |position: isize| {
+        position >= floor as isize
+            && position <= last
+            && lines[position as usize..position as usize + needle.len()]
+                .iter()
+                .zip(needle.iter())
+                .all(|(line, expected)| line == expected)
+    };
+
+    let reach = std::cmp::max(expected - floor as isize, last - expected);
+    for distance in 0..=reach {
+        if matches(expected + distance) {
+            return Some((expected + distance) as usize);
+        }
+
+        if distance > 0 && matches(expected - distance) {
+            return Some((expected - distance) as usize);
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hunks(patch: &str) -> Vec<Hunk> {
+        let mut patches = parse(patch, 1).unwrap();
+        assert_eq!(patches.len(), 1);
+
+        patches.remove(0).hunks
+    }
+
+    fn applied(result: &HunkResult) -> (usize, isize, usize) {
+        match result {
+            HunkResult::Applied { line, offset, fuzz } => (*line, *offset, *fuzz),
+            HunkResult::Rejected => panic!("hunk was rejected"),
+        }
+    }
+
+    const CHANGE_B: &str = "--- a/file\n+++ b/file\n@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n";
+
+    #[test]
+    fn parses_paths_and_hunks_around_other_text() {
+        let patch = "From 1234 Mon Sep 17 00:00:00 2001\n\
+            Subject: [PATCH] change b\n\
+            ---\n file | 2 +-\n\n\
+            diff --git a/file b/file\n"
+            .to_string()
+            + CHANGE_B
+            + "--- /dev/null\n+++ b/new\t2024-01-01 00:00:00\n@@ -0,0 +1 @@\n+new\n";
+        let patches = parse(&patch, 1).unwrap();
+
+        assert_eq!(patches.len(), 2);
+        assert_eq!(patches[0].old_path.as_deref(), Some("file"));
+        assert_eq!(patches[0].new_path.as_deref(), Some("file"));
+        assert_eq!(patches[1].old_path, None);
+        assert_eq!(patches[1].path().map(|p| p.as_str()), Some("new"));
+
+        let hunk = &patches[0].hunks[0];
+        assert_eq!((hunk.source_start, hunk.source_len), (1, 3));
+        assert_eq!(hunk.source_lines(), ["a\n", "b\n", "c\n"]);
+        assert_eq!(hunk.changed_lines(), ["a\n", "B\n", "c\n"]);
+        assert_eq!(
+            hunk.to_unified(),
+            "@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n".to_string()
+        );
+
+        // the length is left off a one line range
+        assert_eq!(patches[1].hunks[0].changed_len, 1);
+    }
+
+    #[test]
+    fn paths_outside_the_repository_are_rejected() {
+        for path in ["b/../escaped.txt", "b/dir/../../escaped.txt", "b/.."] {
+            let patch = format!("--- a/file\n+++ {}\n@@ -1 +1 @@\n-a\n+b\n", path);
+            assert!(parse(&patch, 1)
+                .err()
+                .is_some_and(|e| e.contains("outside the repository")));
+        }
+
+        // with nothing stripped, an absolute path stays absolute
+        let patch = "--- /tmp/file\n+++ /tmp/file\n@@ -1 +1 @@\n-a\n+b\n";
+        assert!(parse(patch, 0).is_err());
+        assert!(parse("Binary files a/x and b/../x differ\n", 1).is_err());
+
+        // dots inside a name are fine
+        let patch = "--- a/file..txt\n+++ b/./file..txt\n@@ -1 +1 @@\n-a\n+b\n";
+        assert!(parse(patch, 1).is_ok());
+    }
+
+    #[test]
+    fn applies_where_the_patch_says() {
+        let (contents, results) = apply("a\nb\nc\n", &hunks(CHANGE_B), 0);
+
+        assert_eq!(contents, "a\nB\nc\n");
+        assert_eq!(applied(&results[0]), (1, 0, 0));
+    }
+
+    #[test]
+    fn finds_a_hunk_that_moved() {
+        let (contents, results) = apply("x\ny\nz\na\nb\nc\n", &hunks(CHANGE_B), 0);
+
+        assert_eq!(contents, "x\ny\nz\na\nB\nc\n");
+        assert_eq!(applied(&results[0]), (4, 3, 0));
+    }
+
+    #[test]
+    fn fuzz_ignores_context_at_the_edges() {
+        let patch = "--- a/file\n+++ b/file\n@@ -1,5 +1,5 @@\n a\n b\n-c\n+C\n d\n e\n";
+
+        // the first line of context doesn't match any more
+        let (contents, results) = apply("A\nb\nc\nd\ne\n", &hunks(patch), 0);
+        assert_eq!(contents, "A\nb\nc\nd\ne\n");
+        assert!(matches!(results[0], HunkResult::Rejected));
+
+        let (contents, results) = apply("A\nb\nc\nd\ne\n", &hunks(patch), 2);
+        assert_eq!(contents, "A\nb\nC\nd\ne\n");
+        assert_eq!(applied(&results[0]), (1, 0, 1));
+    }
+
+    #[test]
+    fn lines_missing_a_newline() {
+        let patch = "--- a/file\n+++ b/file\n@@ -1,2 +1,2 @@\n a\n-b\n\\ No newline at end of file\n+c\n\\ No newline at end of file\n";
+        let parsed = hunks(patch);
+        assert_eq!(parsed[0].source_lines(), ["a\n", "b"]);
+        assert_eq!(parsed[0].changed_lines(), ["a\n", "c"]);
+        assert_eq!(
+            parsed[0].to_unified(),
+            patch.trim_start_matches("--- a/file\n+++ b/file\n")
+        );
+
+        let (contents, results) = apply("a\nb", &parsed, 0);
+        assert_eq!(contents, "a\nc");
+        assert_eq!(applied(&results[0]), (1, 0, 0));
+
+        // a line with its newline doesn't match one without
+        let patch = "--- a/file\n+++ b/file\n@@ -1,2 +1,2 @@\n a\n-b\n+c\n";
+        let (contents, results) = apply("a\nb", &hunks(patch), 0);
+        assert_eq!(contents, "a\nb");
+        assert!(matches!(results[0], HunkResult::Rejected));
+    }
+
+    #[test]
+    fn hunks_have_to_match_their_headers() {
+        // more lines than the header counts
+        let patch = "--- a/file\n+++ b/file\n@@ -1,1 +1,1 @@\n-a\n-b\n+c\n";
+        assert!(parse(patch, 1)
+            .err()
+            .is_some_and(|e| e.contains("doesn't match its header")));
+
+        // fewer lines than the header counts
+        let patch = "--- a/file\n+++ b/file\n@@ -1,3 +1,3 @@\n a\n-b\n+B\n";
+        assert!(parse(patch, 1)
+            .err()
+            .is_some_and(|e| e.contains("ends in the middle of a hunk")));
+
+        assert!(parse("@@ -1 +1 @@\n-a\n+b\n", 1).is_err());
+        assert!(parse("--- a/file\n+++ b/file\n@@ -x +1 @@\n", 1).is_err());
+    }
+
+    #[test]
+    fn a_rejected_hunk_leaves_the_rest_to_apply() {
+        let patch = "--- a/file\n+++ b/file\n\
+            @@ -1,2 +1,2 @@\n-gone\n+here\n a\n\
+            @@ -3,3 +3,3 @@\n b\n-c\n+C\n d\n";
+        let (contents, results) = apply("a\nb\nc\nd\n", &hunks(patch), 0);
+
+        assert_eq!(contents, "a\nb\nC\nd\n");
+        assert!(matches!(results[0], HunkResult::Rejected));
+        assert_eq!(applied(&results[1]), (2, -1, 0));
+    }
+}
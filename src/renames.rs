@@ -0,0 +1,312 @@
+// working out which added files are really moved or copied versions of other files
+//
+// identical contents are paired up by hash first, then what's left is diffed
+// pair by pair and matched up best score first, as long as the score clears
+// the threshold
+
+use crate::diff;
+use crate::files;
+
+// percent similar a pair has to be to count, unless told otherwise
+pub const DEFAULT_THRESHOLD: usize = 50;
+
+// past this many pairs, only identical files are paired up--
+// near matches need a full diff per pair
+const COMPARISON_LIMIT: usize = 1000;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Relation {
+    Renamed,
+    Copied,
+}
+
+// a file an added one might have come from
+pub struct Source<'a> {
+    pub contents: &'a [u8],
+    pub binary: bool,
+    // removed files can be renamed, while ones that are still around can only be copied
+    pub removed: bool,
+}
+
+// an added file
+pub struct Target<'a> {
+    pub contents: &'a [u8],
+    pub binary: bool,
+}
+
+// added file `target` came from `source`, both indices into what was passed in
+pub struct Pairing {
+    pub source: usize,
+    pub target: usize,
+    pub relation: Relation,
+    // percent
+    pub similarity: usize,
+}
+
+fn similarity(source: &[u8], target: &[u8]) -> usize {
+    let (source, target) = match (std::str::from_utf8(source), std::str::from_utf8(target)) {
+        (Ok(source), Ok(target)) => (source, target),
+        _ => return 0,
+    };
+
+    let options = diff::Options {
+        granularity: diff::Granularity::Line,
+        ..Default::default()
+    };
+
+    diff::diff(source.to_string(), target.to_string(), &options).similarity()
+}
+
+pub fn detect(sources: &[Source], targets: &[Target], threshold: usize) -> Vec<Pairing> {
+    let mut pairings = Vec::new();
+    let mut renamed = vec![false; sources.len()];
+    let mut paired = vec![false; targets.len()];
+
+    // a removed file can only be renamed once, anything more is a copy
+    let mut pair = |source: usize, target: usize, similarity: usize, renamed: &mut Vec<bool>| {
+        let relation = if sources[source].removed && !renamed[source] {
+            renamed[source] = true;
+            Relation::Renamed
+        } else {
+            Relation::Copied
+        };
+
+        pairings.push(Pairing {
+            source,
+            target,
+            relation,
+            similarity,
+        });
+    };
+
+    let source_hashes = sources
+        .iter()
+        .map(|s| files::get_hash(&s.contents.to_vec()))
+        .collect::<Vec<String>>();
+    for (target, added) in targets.iter().enumerate() {
+        // every empty file is identical to every other, which says nothing
+        if added.contents.is_empty() {
+            continue;
+        }
+
+        let hash = files::get_hash(&added.contents.to_vec());
+        let matches = (0..sources.len())
+            .filter(|&s| source_hashes[s] == hash)
+            .collect::<Vec<usize>>();
+
+        // a removed file that hasn't been renamed yet makes the better story
+        let best = matches
+            .iter()
+            .find(|&&s| sources[s].removed && !renamed[s])
+            .or(matches.first());
+        if let Some(&source) = best {
+            pair(source, target, 100, &mut renamed);
+            paired[target] = true;
+        }
+    }
+
+    let unpaired = paired.iter().filter(|p| !**p).count();
+    if unpaired * sources.len() > COMPARISON_LIMIT {
+        return pairings;
+    }
+
+    let mut scores = Vec::new();
+    for (target, added) in targets.iter().enumerate() {
+        if paired[target] || added.contents.is_empty() || added.binary {
+            continue;
+        }
+
+        let contents = added.contents;
+        for (source, candidate) in sources.iter().enumerate() {
+            if candidate.binary {
+                continue;
+            }
+
+            // even if the smaller one were entirely inside the bigger one,
+            // a big enough difference in size keeps them under the threshold
+            let (small, big) = match candidate.contents.len() < contents.len() {
+                true => (candidate.contents.len(), contents.len()),
+                false => (contents.len(), candidate.contents.len()),
+            };
+            if big > 0 && small * 2 * 100 / (small + big) < threshold {
+                continue;
+            }
+
+            let score = similarity(candidate.contents, contents);
+            if score >= threshold {
+                scores.push((score, source, target));
+            }
+        }
+    }
+
+    // best matches first, with ties going to whichever came first
+    scores.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)).then(a.2.cmp(&b.2)));
+    for (score, source, target) in scores {
+        if paired[target] {
+            continue;
+        }
+
+        // once a removed file has its rename, its weaker matches aren't worth reporting
+        if sources[source].removed && renamed[source] {
+            continue;
+        }
+
+        pair(source, target, score, &mut renamed);
+        paired[target] = true;
+    }
+
+    pairings.sort_by_key(|p| p.target);
+
+    pairings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(count: usize, changed: usize) -> Vec<u8> {
+        (0..count)
+            .map(|k| match k < changed {
+                true => format!("changed line {}\n", k),
+                false => format!("line {} of the original file\n", k),
+            })
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    fn removed(contents: &[u8]) -> Source<'_> {
+        Source {
+            contents,
+            binary: false,
+            removed: true,
+        }
+    }
+
+    fn kept(contents: &[u8]) -> Source<'_> {
+        Source {
+            contents,
+            binary: false,
+            removed: false,
+        }
+    }
+
+    fn added(contents: &[u8]) -> Target<'_> {
+        Target {
+            contents,
+            binary: false,
+        }
+    }
+
+    #[test]
+    fn identical_contents_pair_up_by_hash() {
+        let old = lines(10, 0);
+        let other = lines(10, 10);
+        let pairings = detect(&[removed(&other), removed(&old)], &[added(&old)], 100);
+
+        assert_eq!(pairings.len(), 1);
+        assert_eq!(pairings[0].source, 1);
+        assert_eq!(pairings[0].target, 0);
+        assert!(pairings[0].relation == Relation::Renamed);
+        assert_eq!(pairings[0].similarity, 100);
+    }
+
+    #[test]
+    fn empty_files_are_never_paired() {
+        let pairings = detect(&[removed(b"")], &[added(b"")], DEFAULT_THRESHOLD);
+
+        assert!(pairings.is_empty());
+    }
+
+    #[test]
+    fn files_still_around_are_copied() {
+        let old = lines(10, 0);
+        let pairings = detect(&[kept(&old)], &[added(&old)], DEFAULT_THRESHOLD);
+
+        assert_eq!(pairings.len(), 1);
+        assert!(pairings[0].relation == Relation::Copied);
+    }
+
+    #[test]
+    fn a_removed_file_is_renamed_once_and_copied_after() {
+        let old = lines(10, 0);
+        let pairings = detect(
+            &[removed(&old)],
+            &[added(&old), added(&old)],
+            DEFAULT_THRESHOLD,
+        );
+
+        assert_eq!(pairings.len(), 2);
+        assert!(pairings[0].relation == Relation::Renamed);
+        assert!(pairings[1].relation == Relation::Copied);
+    }
+
+    #[test]
+    fn near_matches_need_to_clear_the_threshold() {
+        let old = lines(10, 0);
+        let new = lines(10, 2);
+
+        let pairings = detect(&[removed(&old)], &[added(&new)], DEFAULT_THRESHOLD);
+        assert_eq!(pairings.len(), 1);
+        assert!(pairings[0].relation == Relation::Renamed);
+        let similarity = pairings[0].similarity;
+        assert!((DEFAULT_THRESHOLD..100).contains(&similarity));
+
+        assert_eq!(
+            detect(&[removed(&old)], &[added(&new)], similarity).len(),
+            1
+        );
+        assert!(detect(&[removed(&old)], &[added(&new)], similarity + 1).is_empty());
+    }
+
+    #[test]
+    fn the_best_match_wins_a_removed_file() {
+        let old = lines(10, 0);
+        let close = lines(10, 1);
+        let far = lines(10, 4);
+        let pairings = detect(&[removed(&old)], &[added(&far), added(&close)], 10);
+
+        let renamed = pairings
+            .iter()
+            .find(|p| p.relation == Relation::Renamed)
+            .unwrap();
+        assert_eq!(renamed.target, 1);
+    }
+
+    #[test]
+    fn binary_files_are_only_paired_when_identical() {
+        let old = lines(10, 0);
+        let new = lines(10, 1);
+        let flagged = |contents| Target {
+            contents,
+            binary: true,
+        };
+
+        // the flag is taken at its word, without looking at the contents
+        assert!(detect(&[removed(&old)], &[flagged(&new)], 10).is_empty());
+        assert_eq!(detect(&[removed(&old)], &[flagged(&old)], 10).len(), 1);
+    }
+
+    #[test]
+    fn too_many_pairs_only_pairs_identical_files() {
+        let old = lines(10, 0);
+        let new = lines(10, 1);
+        let others = (0..COMPARISON_LIMIT)
+            .map(|k| format!("unrelated file {}\n", k).into_bytes())
+            .collect::<Vec<Vec<u8>>>();
+
+        let mut sources = vec![removed(&old)];
+        sources.extend(others.iter().map(|o| removed(o)));
+
+        // right at the limit, the near match is still found
+        assert_eq!(
+            detect(&sources[..COMPARISON_LIMIT], &[added(&new)], 50).len(),
+            1
+        );
+
+        // one more source puts it over, so only the identical file is paired
+        let pairings = detect(&sources, &[added(&new), added(&others[0])], 50);
+        assert_eq!(pairings.len(), 1);
+        assert_eq!(pairings[0].target, 1);
+        assert_eq!(pairings[0].similarity, 100);
+    }
+}
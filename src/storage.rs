@@ -1,17 +1,42 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::SystemTime;
 use zstd::stream::encode_all;
+use zstd::stream::read::Decoder;
 
-// TODO: there's some obvious error handling
-//       that needs to be done here
-
-// TODO: memory inefficient and frankly pretty gross
-//       can we read files without having
-//       the entire Save in memory? yes, but not today
+use crate::delta;
+use crate::files;
+use crate::parallel;
 
 pub const HASH_LENGTH: usize = 64;
 pub const CREATOR_LENGTH: usize = 32;
 
-const USIZE_LEN: usize = std::mem::size_of::<usize>();
+// every save starts with the magic bytes and a format version,
+// and readers dispatch on the version so older repositories keep loading
+//
+// saves from before the magic was introduced are version 0: no parent link, lengths
+// as the writing host's usize (always 64-bit in practice), only the files staged for
+// that save, and their contents inline after the file headers, which point at them by
+// offset. `recall upgrade` moves the contents into the object store
+//
+// version 1 brought the magic, u64 lengths, parent links, full listings and the
+// object store, version 2 added a flags byte to the end of each file header, and
+// version 3 added the saves merged in to the end of the save headers
+const MAGIC: &[u8; 4] = b"RCLS";
+pub const LEGACY_VERSION: u32 = 0;
+const MAGIC_VERSION: u32 = 1;
+const FILE_FLAGS_VERSION: u32 = 2;
+const MERGE_PARENTS_VERSION: u32 = 3;
+pub const FORMAT_VERSION: u32 = 3;
+
+// file header flags
+const FLAG_BINARY: u8 = 1;
+
+const U32_LEN: usize = std::mem::size_of::<u32>();
+const U64_LEN: usize = std::mem::size_of::<u64>();
 const U128_LEN: usize = std::mem::size_of::<u128>();
 
 type Hash = [u8; HASH_LENGTH];
@@ -27,40 +52,423 @@ macro_rules! to_byte_slice {
     }};
 }
 
+// inverse of `to_byte_slice!`--drops the zero padding
+pub fn from_byte_slice(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+
+    String::from_utf8_lossy(&bytes[..end]).to_string()
+}
+
+#[derive(Debug)]
+pub enum Error {
+    // ran out of bytes partway through the named field
+    Truncated(&'static str),
+    InvalidUtf8(&'static str),
+    Decompression(std::io::Error),
+    Io(std::io::Error),
+    MissingObject(String),
+    CorruptObject(String),
+    // the error happened inside the nth file header of a blob
+    FileHeader(usize, Box<Error>),
+    // a length that doesn't fit in this platform's usize
+    TooLarge(&'static str),
+    UnsupportedVersion(u32),
+    // didn't start with the magic bytes for the named kind of file
+    UnknownFormat(&'static str),
+}
+
+impl std::error::Error for Error {}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Truncated(field) => write!(f, "truncated while reading {}", field),
+            Error::InvalidUtf8(field) => write!(f, "invalid UTF-8 in {}", field),
+            Error::Decompression(e) => write!(f, "failed to decompress: {}", e),
+            Error::Io(e) => write!(f, "failed to read: {}", e),
+            Error::MissingObject(hash) => write!(f, "object {} missing from store", hash),
+            Error::CorruptObject(hash) => write!(f, "object {} is corrupt", hash),
+            Error::FileHeader(index, e) => write!(f, "file header {}: {}", index, e),
+            Error::TooLarge(field) => write!(f, "{} is too large for this platform", field),
+            Error::UnsupportedVersion(version) => write!(
+                f,
+                "format version {} is newer than this version of recall supports",
+                version
+            ),
+            Error::UnknownFormat(kind) => write!(f, "not a recall {} file", kind),
+        }
+    }
+}
+
 macro_rules! read_to_slice {
-    ($bytes:expr, $cursor:expr, $length:expr) => {{
-        let data = read($bytes, $cursor, $length);
+    ($bytes:expr, $cursor:expr, $length:expr, $field:expr) => {{
+        let data = read($bytes, $cursor, $length, $field)?;
 
         to_byte_slice!(data, $length)
     }};
 }
 
 macro_rules! read_to_value {
-    ($bytes:expr, $cursor:expr, $length:expr, $type:ty) => {{
-        let data = match read($bytes, $cursor, $length).try_into() {
-            Ok(data) => data,
-            Err(e) => panic!("Failed to convert bytes to type: {:?}", e),
-        };
+    ($bytes:expr, $cursor:expr, $length:expr, $type:ty, $field:expr) => {{
+        let data = read($bytes, $cursor, $length, $field)?
+            .try_into()
+            .map_err(|_| Error::Truncated($field))?;
 
         <$type>::from_be_bytes(data)
     }};
 }
 
-fn read(bytes: &Vec<u8>, cursor: &mut usize, length: usize) -> Vec<u8> {
-    let data = bytes[*cursor..*cursor + length].to_vec();
-    *cursor += length;
+// lengths are always stored as u64, whatever the host's usize is
+macro_rules! read_to_length {
+    ($bytes:expr, $cursor:expr, $field:expr) => {{
+        let length = read_to_value!($bytes, $cursor, U64_LEN, u64, $field);
 
-    data
+        usize::try_from(length).map_err(|_| Error::TooLarge($field))?
+    }};
 }
 
-#[derive(Debug)]
+macro_rules! read_to_string {
+    ($bytes:expr, $cursor:expr, $length:expr, $field:expr) => {{
+        let data = read($bytes, $cursor, $length, $field)?;
+
+        String::from_utf8(data).map_err(|_| Error::InvalidUtf8($field))?
+    }};
+}
+
+fn read(
+    bytes: &[u8],
+    cursor: &mut usize,
+    length: usize,
+    field: &'static str,
+) -> Result<Vec<u8>, Error> {
+    let end = match cursor.checked_add(length) {
+        Some(end) if end <= bytes.len() => end,
+        _ => return Err(Error::Truncated(field)),
+    };
+
+    let data = bytes[*cursor..end].to_vec();
+    *cursor = end;
+
+    Ok(data)
+}
+
+// file contents live in a content-addressed store, keyed by SHA-256
+//
+// an object is either the full contents zstd-compressed, or a delta marker
+// followed by the compressed base hash, chain depth, and delta ops
+pub const OBJECTS_DIR: &str = ".recall/objects";
+
+const DELTA_MARKER: u8 = b'D';
+
+// past this, a full copy is written regardless--bounds how many
+// objects need to be read to reconstruct any one file
+const MAX_DELTA_CHAIN: usize = 10;
+
+// deltas need both versions in memory, so big files are always stored whole
+const DELTA_MAX_SIZE: usize = 4 * 1024 * 1024;
+
+// how many earlier versions of a path repacking will try as a delta base
+const REPACK_WINDOW: usize = 8;
+
+fn object_path(root: &Path, hash: &str) -> PathBuf {
+    root.join(&hash[..2]).join(&hash[2..])
+}
+
+pub fn has_object(hash: &str) -> bool {
+    object_path(Path::new(OBJECTS_DIR), hash).exists()
+}
+
+fn write_object_file(root: &Path, hash: &str, encoded: &[u8]) {
+    let path = object_path(root, hash);
+    std::fs::create_dir_all(path.parent().unwrap()).expect("Failed to create directory");
+
+    // write to a temporary file first so a crash can't leave a partial object behind--
+    // each write gets its own, since two threads can be storing the same contents at once
+    static TEMP_COUNT: AtomicUsize = AtomicUsize::new(0);
+    let temp_path = path.with_extension(format!(
+        "{}.{}.tmp",
+        std::process::id(),
+        TEMP_COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::write(&temp_path, encoded).expect("Failed to write object");
+    std::fs::rename(&temp_path, &path).expect("Failed to write object");
+}
+
+// the delta encoding of contents against the given base,
+// provided that's allowed and actually an improvement over the full copy
+fn encode_delta(
+    contents: &[u8],
+    base: &str,
+    base_contents: &[u8],
+    base_depth: usize,
+) -> Option<Vec<u8>> {
+    if base_depth + 1 > MAX_DELTA_CHAIN
+        || contents.len() > DELTA_MAX_SIZE
+        || base_contents.len() > DELTA_MAX_SIZE
+    {
+        return None;
+    }
+
+    let ops = delta::compute(base_contents, contents);
+
+    let mut payload = Vec::new();
+    payload.extend_from_slice(base.as_bytes());
+    payload.extend_from_slice(&((base_depth + 1) as u64).to_be_bytes());
+    payload.extend_from_slice(&delta::to_bytes(&ops));
+
+    let mut encoded = vec![DELTA_MARKER];
+    encoded.extend(encode_full(&payload));
+
+    Some(encoded)
+}
+
+fn encode_full(contents: &[u8]) -> Vec<u8> {
+    encode_all(contents, 3).expect("Failed to compress object")
+}
+
+fn smallest(full: Vec<u8>, delta: Option<Vec<u8>>) -> Vec<u8> {
+    match delta {
+        Some(delta) if delta.len() < full.len() => delta,
+        _ => full,
+    }
+}
+
+// writes the contents to the object store, returning its hash
+// identical contents are only ever stored once
+//
+// if given the hash of the previous version of the file,
+// the contents will be stored as a delta against it where worthwhile
+pub fn write_object(contents: &Vec<u8>, base: Option<&str>) -> String {
+    let hash = files::get_hash(contents);
+    if has_object(&hash) {
+        return hash;
+    }
+
+    let root = Path::new(OBJECTS_DIR);
+    let delta = base.and_then(|base| {
+        let base_depth = object_depth(root, base).ok()?;
+        let base_contents = read_object_from(root, base).ok()?;
+
+        encode_delta(contents, base, &base_contents, base_depth)
+    });
+
+    write_object_file(root, &hash, &smallest(encode_full(contents), delta));
+
+    hash
+}
+
+// (base hash, chain depth, delta ops) for delta objects
+fn decode_delta(hash: &str, encoded: &[u8]) -> Result<(String, usize, Vec<delta::DeltaOp>), Error> {
+    let corrupt = || Error::CorruptObject(hash.to_string());
+
+    let payload = zstd::stream::decode_all(encoded).map_err(Error::Decompression)?;
+    if payload.len() < HASH_LENGTH + 8 {
+        return Err(corrupt());
+    }
+
+    let base = String::from_utf8(payload[..HASH_LENGTH].to_vec()).map_err(|_| corrupt())?;
+    let depth = u64::from_be_bytes(payload[HASH_LENGTH..HASH_LENGTH + 8].try_into().unwrap());
+    let ops = delta::from_bytes(&payload[HASH_LENGTH + 8..]).ok_or_else(corrupt)?;
+
+    Ok((base, depth as usize, ops))
+}
+
+fn read_object_file(root: &Path, hash: &str) -> Result<Vec<u8>, Error> {
+    std::fs::read(object_path(root, hash)).map_err(|_| Error::MissingObject(hash.to_string()))
+}
+
+// how many deltas deep the object is--full objects are 0
+fn object_depth(root: &Path, hash: &str) -> Result<usize, Error> {
+    let encoded = read_object_file(root, hash)?;
+    if encoded.first() == Some(&DELTA_MARKER) {
+        Ok(decode_delta(hash, &encoded[1..])?.1)
+    } else {
+        Ok(0)
+    }
+}
+
+fn read_object_from(root: &Path, hash: &str) -> Result<Vec<u8>, Error> {
+    let encoded = read_object_file(root, hash)?;
+    if encoded.first() == Some(&DELTA_MARKER) {
+        let (base, _, ops) = decode_delta(hash, &encoded[1..])?;
+        let base_contents = read_object_from(root, &base)?;
+
+        delta::apply(&base_contents, &ops).ok_or_else(|| Error::CorruptObject(hash.to_string()))
+    } else {
+        zstd::stream::decode_all(&encoded[..]).map_err(Error::Decompression)
+    }
+}
+
+// full contents of the object, reconstructing it from its delta chain if need be
+pub fn read_object(hash: &str) -> Result<Vec<u8>, Error> {
+    read_object_from(Path::new(OBJECTS_DIR), hash)
+}
+
+// a reader over the object's contents
+//
+// full objects are decompressed as they're read, so arbitrarily large files
+// stream in bounded memory--only deltas, which are capped in size, get rebuilt up front
+pub fn open_object(hash: &str) -> Result<Box<dyn Read>, Error> {
+    let root = Path::new(OBJECTS_DIR);
+    let file =
+        File::open(object_path(root, hash)).map_err(|_| Error::MissingObject(hash.to_string()))?;
+
+    let mut reader = BufReader::new(file);
+    let is_delta = reader.fill_buf().map_err(Error::Io)?.first() == Some(&DELTA_MARKER);
+    if is_delta {
+        Ok(Box::new(std::io::Cursor::new(read_object_from(
+            root, hash,
+        )?)))
+    } else {
+        Ok(Box::new(
+            Decoder::with_buffer(reader).map_err(Error::Decompression)?,
+        ))
+    }
+}
+
+// the length of the object's contents, without holding them all in memory
+fn object_length(hash: &str) -> Result<usize, Error> {
+    let mut object = open_object(hash)?;
+    let length = std::io::copy(&mut object, &mut std::io::sink()).map_err(Error::Io)?;
+
+    usize::try_from(length).map_err(|_| Error::TooLarge("object length"))
+}
+
+fn list_objects(root: &Path) -> Vec<String> {
+    let mut objects = Vec::new();
+    for dir in std::fs::read_dir(root).expect("Failed to read directory") {
+        let dir = dir.expect("Failed to read entry").path();
+        let prefix = dir.file_name().unwrap().to_string_lossy().to_string();
+        for entry in std::fs::read_dir(&dir).expect("Failed to read directory") {
+            let name = entry.expect("Failed to read entry").file_name();
+            objects.push(prefix.clone() + &name.to_string_lossy());
+        }
+    }
+
+    objects
+}
+
+// removes every object not reachable from the given hashes,
+// keeping whatever delta bases the reachable ones depend on
+pub fn collect_garbage(reachable: &[String]) -> usize {
+    let root = Path::new(OBJECTS_DIR);
+
+    let mut keep = std::collections::HashSet::new();
+    for hash in reachable.iter() {
+        let mut next = Some(hash.clone());
+        while let Some(hash) = next {
+            if !keep.insert(hash.clone()) {
+                break;
+            }
+
+            next = match read_object_file(root, &hash) {
+                Ok(encoded) if encoded.first() == Some(&DELTA_MARKER) => {
+                    decode_delta(&hash, &encoded[1..]).ok().map(|d| d.0)
+                }
+                _ => None,
+            };
+        }
+    }
+
+    let mut removed = 0;
+    for hash in list_objects(root) {
+        if !keep.contains(&hash) {
+            std::fs::remove_file(object_path(root, &hash)).expect("Failed to remove object");
+            removed += 1;
+        }
+    }
+
+    removed
+}
+
+// repacking builds the new store alongside the old one, then swaps them over
+const REPACK_DIR: &str = ".recall/objects.repack";
+const OLD_OBJECTS_DIR: &str = ".recall/objects.old";
+
+// puts a store back in place if a repack was interrupted partway through swapping them over
+//
+// the new store is only moved into place once it's complete, so whichever of the two
+// is still around will do, and the new one is preferred
+pub fn recover_objects() {
+    let root = Path::new(OBJECTS_DIR);
+    if root.exists() {
+        return;
+    }
+
+    for stored in [REPACK_DIR, OLD_OBJECTS_DIR] {
+        if Path::new(stored).exists() {
+            std::fs::rename(stored, root).expect("Failed to move directory");
+            return;
+        }
+    }
+}
+
+// rewrites the store from scratch given every version of every path in history order,
+// picking for each object whichever recent version of its path makes the smallest delta
+//
+// anything not mentioned is dropped
+pub fn repack(versions: &[Vec<String>]) -> Result<usize, Error> {
+    let root = Path::new(OBJECTS_DIR);
+    let repack_root = Path::new(REPACK_DIR);
+    let old_root = Path::new(OLD_OBJECTS_DIR);
+
+    // with a store in place, anything else left over from an earlier repack
+    // is either half-written or already replaced
+    recover_objects();
+    for leftover in [repack_root, old_root] {
+        if leftover.exists() {
+            std::fs::remove_dir_all(leftover).expect("Failed to remove directory");
+        }
+    }
+
+    let mut depths = std::collections::HashMap::new();
+    for path_versions in versions.iter() {
+        for (i, hash) in path_versions.iter().enumerate() {
+            if depths.contains_key(hash) {
+                continue;
+            }
+
+            let contents = read_object_from(root, hash)?;
+            let mut best = (encode_full(&contents), 0);
+            for base in path_versions[i.saturating_sub(REPACK_WINDOW)..i].iter() {
+                let base_depth = match depths.get(base) {
+                    Some(depth) => *depth,
+                    None => continue,
+                };
+
+                let base_contents = read_object_from(root, base)?;
+                if let Some(delta) = encode_delta(&contents, base, &base_contents, base_depth) {
+                    if delta.len() < best.0.len() {
+                        best = (delta, base_depth + 1);
+                    }
+                }
+            }
+
+            write_object_file(repack_root, hash, &best.0);
+            depths.insert(hash.clone(), best.1);
+        }
+    }
+
+    // there's always a complete store at one of these paths, whenever this stops
+    std::fs::create_dir_all(repack_root).expect("Failed to create directory");
+    std::fs::rename(root, old_root).expect("Failed to move directory");
+    std::fs::rename(repack_root, root).expect("Failed to move directory");
+    std::fs::remove_dir_all(old_root).expect("Failed to remove directory");
+
+    Ok(depths.len())
+}
+
+#[derive(Debug, Clone)]
 pub struct FileHeaders {
     pub last_modified: u128,
     pub created: u128,
     pub content_length: usize,
     pub filename_length: usize,
     pub filename: String,
-    pub content_location: usize,
+    pub hash: Hash,
+    // decided when the file was staged--None in saves from before version 2,
+    // until `recall upgrade` works it out
+    pub binary: Option<bool>,
 }
 
 impl FileHeaders {
@@ -68,185 +476,381 @@ impl FileHeaders {
         let mut bytes = Vec::new();
         bytes.extend_from_slice(&self.last_modified.to_be_bytes());
         bytes.extend_from_slice(&self.created.to_be_bytes());
-        bytes.extend_from_slice(&self.content_length.to_be_bytes());
-        bytes.extend_from_slice(&self.filename_length.to_be_bytes());
+        bytes.extend_from_slice(&(self.content_length as u64).to_be_bytes());
+        bytes.extend_from_slice(&(self.filename_length as u64).to_be_bytes());
         bytes.extend_from_slice(self.filename.as_bytes());
-        bytes.extend_from_slice(&self.content_location.to_be_bytes());
+        bytes.extend_from_slice(&self.hash);
+        bytes.push(if self.binary == Some(true) {
+            FLAG_BINARY
+        } else {
+            0
+        });
 
         bytes
     }
 
-    fn from_bytes(bytes: &Vec<u8>) -> FileHeaders {
-        let mut cursor = 0;
-        let last_modified = read_to_value!(&bytes, &mut cursor, U128_LEN, u128);
-        let created = read_to_value!(&bytes, &mut cursor, U128_LEN, u128);
-        let content_length = read_to_value!(&bytes, &mut cursor, USIZE_LEN, usize);
-        let filename_length = read_to_value!(&bytes, &mut cursor, USIZE_LEN, usize);
-        let filename = String::from_utf8(read(&bytes, &mut cursor, filename_length)).unwrap();
-        let content_location = read_to_value!(&bytes, &mut cursor, USIZE_LEN, usize);
+    fn from_bytes(bytes: &[u8], cursor: &mut usize, version: u32) -> Result<FileHeaders, Error> {
+        match version {
+            MAGIC_VERSION..=FORMAT_VERSION => {
+                let last_modified = read_to_value!(bytes, cursor, U128_LEN, u128, "last modified");
+                let created = read_to_value!(bytes, cursor, U128_LEN, u128, "created");
+                let content_length = read_to_length!(bytes, cursor, "content length");
+                let filename_length = read_to_length!(bytes, cursor, "filename length");
+                let filename = read_to_string!(bytes, cursor, filename_length, "filename");
+                let hash = read_to_slice!(bytes, cursor, HASH_LENGTH, "file hash");
+                let flags = if version >= FILE_FLAGS_VERSION {
+                    Some(read_to_value!(bytes, cursor, 1, u8, "file flags"))
+                } else {
+                    None
+                };
 
-        FileHeaders {
+                Ok(FileHeaders {
+                    last_modified,
+                    created,
+                    content_length,
+                    filename_length,
+                    filename,
+                    hash,
+                    binary: flags.map(|flags| flags & FLAG_BINARY != 0),
+                })
+            }
+            _ => Err(Error::UnsupportedVersion(version)),
+        }
+    }
+
+    // a version 0 header, and the offset of its contents--
+    // the hash is left zeroed, since only the contents can tell what it is
+    fn from_legacy_bytes(bytes: &[u8], cursor: &mut usize) -> Result<(FileHeaders, usize), Error> {
+        let last_modified = read_to_value!(bytes, cursor, U128_LEN, u128, "last modified");
+        let created = read_to_value!(bytes, cursor, U128_LEN, u128, "created");
+        let content_length = read_to_length!(bytes, cursor, "content length");
+        let filename_length = read_to_length!(bytes, cursor, "filename length");
+        let filename = read_to_string!(bytes, cursor, filename_length, "filename");
+        let content_location = read_to_length!(bytes, cursor, "content location");
+
+        let header = FileHeaders {
             last_modified,
             created,
             content_length,
             filename_length,
             filename,
-            content_location,
-        }
+            hash: [0; HASH_LENGTH],
+            binary: None,
+        };
+
+        Ok((header, content_location))
     }
 
-    fn len(&self) -> usize {
-        U128_LEN + U128_LEN + USIZE_LEN + USIZE_LEN + self.filename_length + USIZE_LEN
+    pub fn content_hash(&self) -> String {
+        from_byte_slice(&self.hash)
     }
 }
 
+// a blob is only the listing of a snapshot--the contents themselves are in the object store
 #[derive(Debug)]
 pub struct Blob {
     pub headers: Vec<FileHeaders>,
-    pub data: Vec<u8>,
 }
 
 // NOTE: this is _always_ compressed with the headers included
 impl Blob {
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::new();
-        let headers_size: usize = self.headers.iter().map(|h| h.len()).sum();
-
-        bytes.extend_from_slice(&headers_size.to_be_bytes());
+        let mut headers = Vec::new();
         for header in &self.headers {
-            bytes.extend_from_slice(&header.to_bytes());
+            headers.extend_from_slice(&header.to_bytes());
         }
 
-        bytes.extend_from_slice(&self.data);
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&(headers.len() as u64).to_be_bytes());
+        bytes.extend_from_slice(&headers);
+
         let compressed_bytes = encode_all(&bytes as &[u8], 3).unwrap();
 
         compressed_bytes
     }
 
-    fn headers_from_bytes(bytes: &Vec<u8>) -> Vec<FileHeaders> {
+    fn headers_from_bytes(bytes: &[u8], version: u32) -> Result<Vec<FileHeaders>, Error> {
         let mut cursor = 0;
-        let headers_size = read_to_value!(&bytes, &mut cursor, USIZE_LEN, usize);
+        let headers_size = read_to_length!(bytes, &mut cursor, "headers size");
+        let headers_end = match headers_size.checked_add(cursor) {
+            Some(end) if end <= bytes.len() => end,
+            _ => return Err(Error::Truncated("file headers")),
+        };
+
+        let bytes = &bytes[..headers_end];
         let mut headers = Vec::new();
-        while cursor < headers_size {
-            let header = FileHeaders::from_bytes(&bytes[cursor..].to_vec());
-            cursor += header.len();
+        while cursor < headers_end {
+            let header = FileHeaders::from_bytes(bytes, &mut cursor, version)
+                .map_err(|e| Error::FileHeader(headers.len(), Box::new(e)))?;
             headers.push(header);
         }
 
-        headers
+        Ok(headers)
     }
 
-    pub fn from_bytes(bytes: &Vec<u8>) -> Blob {
-        let decompressed_bytes = zstd::stream::decode_all(&bytes[..]).unwrap();
-        let headers = Blob::headers_from_bytes(&decompressed_bytes);
-        let headers_size = headers.iter().map(|h| h.len()).sum::<usize>() + USIZE_LEN;
-        let data = decompressed_bytes[headers_size..].to_vec();
+    pub fn from_bytes(bytes: &[u8], version: u32) -> Result<Blob, Error> {
+        if version == LEGACY_VERSION {
+            let (headers, _) = Blob::legacy_from_bytes(bytes)?;
+            return Ok(Blob { headers });
+        }
 
-        Blob { headers, data }
+        let decompressed_bytes = zstd::stream::decode_all(bytes).map_err(Error::Decompression)?;
+        let headers = Blob::headers_from_bytes(&decompressed_bytes, version)?;
+
+        Ok(Blob { headers })
     }
 
-    pub fn get_file(&self, filename: &str) -> Option<Vec<u8>> {
-        for header in &self.headers {
-            if header.filename == filename {
-                return Some(
-                    self.data
-                        [header.content_location..header.content_location + header.content_length]
-                        .to_vec(),
-                );
-            }
+    // the headers of a version 0 blob, hashed from the contents that follow them,
+    // along with those contents in the same order
+    fn legacy_from_bytes(bytes: &[u8]) -> Result<(Vec<FileHeaders>, Vec<Vec<u8>>), Error> {
+        let bytes = zstd::stream::decode_all(bytes).map_err(Error::Decompression)?;
+
+        let mut cursor = 0;
+        let headers_size = read_to_length!(&bytes, &mut cursor, "headers size");
+        let headers_end = match headers_size.checked_add(cursor) {
+            Some(end) if end <= bytes.len() => end,
+            _ => return Err(Error::Truncated("file headers")),
+        };
+
+        let data = &bytes[headers_end..];
+        let mut headers = Vec::new();
+        let mut contents = Vec::new();
+        while cursor < headers_end {
+            let file_error = |e| Error::FileHeader(headers.len(), Box::new(e));
+            let (mut header, location) =
+                FileHeaders::from_legacy_bytes(&bytes[..headers_end], &mut cursor)
+                    .map_err(file_error)?;
+
+            let content = match location.checked_add(header.content_length) {
+                Some(end) if end <= data.len() => data[location..end].to_vec(),
+                _ => return Err(file_error(Error::Truncated("file contents"))),
+            };
+
+            header.hash = to_byte_slice!(files::get_hash(&content).as_bytes(), HASH_LENGTH);
+            headers.push(header);
+            contents.push(content);
         }
 
-        None
+        Ok((headers, contents))
+    }
+
+    pub fn get_header(&self, filename: &str) -> Option<&FileHeaders> {
+        self.headers.iter().find(|h| h.filename == filename)
+    }
+
+    // Ok(None) if the file isn't part of this blob
+    pub fn get_file(&self, filename: &str) -> Result<Option<Vec<u8>>, Error> {
+        match self.get_header(filename) {
+            Some(header) => Ok(Some(read_object(&header.content_hash())?)),
+            None => Ok(None),
+        }
     }
 }
 
-pub fn blobify(files: Vec<String>) -> Blob {
+// builds the listing for a new save: the parent's entries carried forward unchanged,
+// minus `removed`, with `staged` on top
+//
+// staged files are already in the object store, so a save records exactly what was
+// staged even if the working copy has moved on since. anything staged before stage
+// wrote objects has none, and is read from disk instead
+//
+// new objects are written, and existing ones measured, on up to `jobs` threads--
+// headers come out sorted by filename either way
+pub fn blobify(
+    staged: Vec<files::StagedFile>,
+    removed: &[String],
+    parent: Option<&Blob>,
+    jobs: usize,
+) -> Result<Blob, Error> {
     let mut headers = Vec::new();
-    let mut data = Vec::new();
-    let mut headers_size = 0;
-    for f in files {
-        let metadata = std::fs::metadata(&f).unwrap();
-        let filename_bytes = f.as_bytes();
-        let blob_headers = FileHeaders {
-            last_modified: metadata
-                .modified()
-                .unwrap()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_micros(),
-            created: metadata
-                .created()
-                .unwrap()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_micros(),
-            content_length: metadata.len() as usize,
-            filename_length: filename_bytes.len(),
-            filename: f.clone(),
-            content_location: 0,
+    if let Some(parent) = parent {
+        let replaced = staged
+            .iter()
+            .map(|f| f.filename.as_str())
+            .chain(removed.iter().map(|f| f.as_str()))
+            .collect::<HashSet<&str>>();
+        for header in parent.headers.iter() {
+            if !replaced.contains(header.filename.as_str()) {
+                headers.push(header.clone());
+            }
+        }
+    }
+
+    let timestamp = |time: std::io::Result<SystemTime>| {
+        time.ok()
+            .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .unwrap_or_default()
+            .as_micros()
+    };
+
+    let header = |f: &files::StagedFile| -> Result<FileHeaders, Error> {
+        let (hash, content_length, binary) = if has_object(&f.hash) {
+            (f.hash.clone(), object_length(&f.hash)?, f.binary)
+        } else {
+            let datum: Vec<u8> = std::fs::read(&f.filename).map_err(Error::Io)?;
+
+            // new versions are stored against the last saved version of the same path
+            let base = parent
+                .and_then(|p| p.get_header(&f.filename))
+                .map(|h| h.content_hash());
+
+            (
+                write_object(&datum, base.as_deref()),
+                datum.len(),
+                files::is_binary(&datum),
+            )
         };
 
-        let datum: Vec<u8> = std::fs::read(&f).unwrap();
+        // the working copy's timestamps, or now if it's gone
+        let (last_modified, created) = match std::fs::metadata(&f.filename) {
+            Ok(metadata) => (
+                timestamp(metadata.modified()),
+                timestamp(metadata.created()),
+            ),
+            Err(_) => (
+                timestamp(Ok(SystemTime::now())),
+                timestamp(Ok(SystemTime::now())),
+            ),
+        };
 
-        headers_size += blob_headers.to_bytes().len();
+        let filename_bytes = f.filename.as_bytes();
+        Ok(FileHeaders {
+            last_modified,
+            created,
+            content_length,
+            filename_length: filename_bytes.len(),
+            filename: f.filename.clone(),
+            hash: to_byte_slice!(hash.as_bytes(), HASH_LENGTH),
+            binary: Some(binary),
+        })
+    };
 
-        headers.push(blob_headers);
-        data.push(datum);
+    for header in parallel::map(&staged, jobs, header) {
+        headers.push(header?);
     }
 
-    let mut content_offset = headers_size;
-    let mut blob_data = Vec::new();
-    for i in 0..data.len() {
-        headers[i].content_location = content_offset - headers_size;
-        content_offset += data[i].len();
-        blob_data.extend_from_slice(&data[i]);
-    }
+    headers.sort_by(|a, b| a.filename.cmp(&b.filename));
 
-    Blob {
-        headers,
-        data: blob_data,
-    }
+    Ok(Blob { headers })
 }
 
 pub struct SaveHeaders {
     pub hash: Hash,
+    // zeroed for the first save in a repository
+    pub parent: Hash,
     pub memo: String,
     pub memo_size: usize,
     pub created_date: u128,
     pub creator: Creator,
+    // the other saves a merge brought in, besides the parent
+    pub merged: Vec<Hash>,
 }
 
 impl SaveHeaders {
     fn to_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
         bytes.extend_from_slice(&self.hash);
-        bytes.extend_from_slice(&self.memo_size.to_be_bytes());
+        bytes.extend_from_slice(&self.parent);
+        bytes.extend_from_slice(&(self.memo_size as u64).to_be_bytes());
         bytes.extend_from_slice(self.memo.as_bytes());
         bytes.extend_from_slice(&self.created_date.to_be_bytes());
         bytes.extend_from_slice(&self.creator);
+        bytes.extend_from_slice(&(self.merged.len() as u64).to_be_bytes());
+        for merged in self.merged.iter() {
+            bytes.extend_from_slice(merged);
+        }
 
         bytes
     }
 
-    fn from_bytes(bytes: &Vec<u8>) -> SaveHeaders {
-        let mut cursor = 0;
-        let hash = read_to_slice!(&bytes, &mut cursor, HASH_LENGTH);
-        let memo_size = read_to_value!(&bytes, &mut cursor, USIZE_LEN, usize);
-        let memo = String::from_utf8(read(&bytes, &mut cursor, memo_size)).unwrap();
-        let created_date = read_to_value!(&bytes, &mut cursor, U128_LEN, u128);
-        let creator = read_to_slice!(&bytes, &mut cursor, CREATOR_LENGTH);
-
-        SaveHeaders {
-            hash,
-            memo_size,
-            memo,
-            created_date,
-            creator,
+    // everything but the hash itself, laid out the same regardless of format version
+    //
+    // saves that didn't merge anything hash the same as they did before merges were recorded
+    fn hashable_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.parent);
+        bytes.extend_from_slice(&(self.memo_size as u64).to_be_bytes());
+        bytes.extend_from_slice(self.memo.as_bytes());
+        bytes.extend_from_slice(&self.created_date.to_be_bytes());
+        bytes.extend_from_slice(&self.creator);
+        if !self.merged.is_empty() {
+            bytes.extend_from_slice(&(self.merged.len() as u64).to_be_bytes());
+            for merged in self.merged.iter() {
+                bytes.extend_from_slice(merged);
+            }
+        }
+
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8], cursor: &mut usize, version: u32) -> Result<SaveHeaders, Error> {
+        match version {
+            LEGACY_VERSION => {
+                let hash = read_to_slice!(bytes, cursor, HASH_LENGTH, "save hash");
+                let memo_size = read_to_length!(bytes, cursor, "memo size");
+                let memo = read_to_string!(bytes, cursor, memo_size, "memo");
+                let created_date = read_to_value!(bytes, cursor, U128_LEN, u128, "created date");
+                let creator = read_to_slice!(bytes, cursor, CREATOR_LENGTH, "creator");
+
+                Ok(SaveHeaders {
+                    hash,
+                    parent: [0; HASH_LENGTH],
+                    memo_size,
+                    memo,
+                    created_date,
+                    creator,
+                    merged: Vec::new(),
+                })
+            }
+            MAGIC_VERSION..=FORMAT_VERSION => {
+                let hash = read_to_slice!(bytes, cursor, HASH_LENGTH, "save hash");
+                let parent = read_to_slice!(bytes, cursor, HASH_LENGTH, "parent hash");
+                let memo_size = read_to_length!(bytes, cursor, "memo size");
+                let memo = read_to_string!(bytes, cursor, memo_size, "memo");
+                let created_date = read_to_value!(bytes, cursor, U128_LEN, u128, "created date");
+                let creator = read_to_slice!(bytes, cursor, CREATOR_LENGTH, "creator");
+
+                // earlier versions kept merges in .recall/merges
+                let mut merged = Vec::new();
+                if version >= MERGE_PARENTS_VERSION {
+                    let merged_count = read_to_length!(bytes, cursor, "merged count");
+                    for _ in 0..merged_count {
+                        merged.push(read_to_slice!(bytes, cursor, HASH_LENGTH, "merged hash"));
+                    }
+                }
+
+                Ok(SaveHeaders {
+                    hash,
+                    parent,
+                    memo_size,
+                    memo,
+                    created_date,
+                    creator,
+                    merged,
+                })
+            }
+            _ => Err(Error::UnsupportedVersion(version)),
         }
     }
 
-    fn len(&self) -> usize {
-        HASH_LENGTH + USIZE_LEN + self.memo_size + U128_LEN + CREATOR_LENGTH
+    pub fn parent(&self) -> Option<String> {
+        let parent = from_byte_slice(&self.parent);
+        if parent.is_empty() {
+            None
+        } else {
+            Some(parent)
+        }
+    }
+
+    pub fn merged(&self) -> Vec<String> {
+        self.merged.iter().map(|m| from_byte_slice(m)).collect()
+    }
+
+    // the parent, then any saves merged in
+    pub fn parents(&self) -> Vec<String> {
+        let mut parents = self.parent().into_iter().collect::<Vec<String>>();
+        parents.extend(self.merged());
+        parents
     }
 }
 
@@ -256,17 +860,520 @@ pub struct Save {
 }
 
 impl Save {
+    // always written in the current format version
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = self.headers.to_bytes();
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&FORMAT_VERSION.to_be_bytes());
+        bytes.extend_from_slice(&self.headers.to_bytes());
         bytes.extend_from_slice(&self.blob.to_bytes());
 
         bytes
     }
 
-    pub fn from_bytes(bytes: &Vec<u8>) -> Save {
-        let headers = SaveHeaders::from_bytes(bytes);
-        let blob = Blob::from_bytes(&bytes[headers.len()..].to_vec());
+    // the save's ID is derived from its headers and the files it contains,
+    // so recomputing it is enough to tell whether a save has been tampered with
+    pub fn compute_hash(&self) -> String {
+        let mut bytes = self.headers.hashable_bytes();
+
+        let mut files = self
+            .blob
+            .headers
+            .iter()
+            .map(|h| (&h.filename, &h.hash))
+            .collect::<Vec<(&String, &Hash)>>();
+        files.sort();
+
+        for (filename, hash) in files {
+            bytes.extend_from_slice(&(filename.len() as u64).to_be_bytes());
+            bytes.extend_from_slice(filename.as_bytes());
+            bytes.extend_from_slice(hash);
+        }
+
+        files::get_hash(&bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Save, Error> {
+        let mut cursor = 0;
+        let version = read_version(bytes, &mut cursor)?;
+        let headers = SaveHeaders::from_bytes(bytes, &mut cursor, version)?;
+        let blob = Blob::from_bytes(&bytes[cursor..], version)?;
+
+        Ok(Save { headers, blob })
+    }
+
+    // a version 0 save, along with the file contents it kept inline, in the order of its headers
+    pub fn from_legacy_bytes(bytes: &[u8]) -> Result<(Save, Vec<Vec<u8>>), Error> {
+        let mut cursor = 0;
+        let headers = SaveHeaders::from_bytes(bytes, &mut cursor, LEGACY_VERSION)?;
+        let (file_headers, contents) = Blob::legacy_from_bytes(&bytes[cursor..])?;
+
+        let blob = Blob {
+            headers: file_headers,
+        };
+
+        Ok((Save { headers, blob }, contents))
+    }
+}
+
+// the format version of a serialized save, leaving the cursor just past the file header
+//
+// legacy saves start with their hex hash, so they can't be mistaken for the magic
+pub fn read_version(bytes: &[u8], cursor: &mut usize) -> Result<u32, Error> {
+    if !bytes.starts_with(MAGIC) {
+        return Ok(LEGACY_VERSION);
+    }
+
+    *cursor += MAGIC.len();
+    let version = read_to_value!(bytes, cursor, U32_LEN, u32, "format version");
+    if version > FORMAT_VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+
+    Ok(version)
+}
+
+// reads up to length bytes, fewer only if the reader runs dry
+//
+// callers hand whatever comes back to the regular decoders,
+// which report exactly which field was cut short
+fn read_up_to(reader: &mut impl Read, length: u64) -> Result<Vec<u8>, Error> {
+    let mut bytes = Vec::new();
+    reader
+        .take(length)
+        .read_to_end(&mut bytes)
+        .map_err(Error::Decompression)?;
+
+    Ok(bytes)
+}
+
+const FILE_HEADER_FIXED_LEN: usize = U128_LEN + U128_LEN + U64_LEN + U64_LEN;
+
+// reads a save incrementally: the save headers up front, then the file headers
+// one at a time as they're decompressed, so listing a save never needs the whole blob
+pub struct SaveReader {
+    pub headers: SaveHeaders,
+    pub version: u32,
+    files: FileListing,
+    index: usize,
+}
+
+enum FileListing {
+    Streamed {
+        decoder: Decoder<'static, BufReader<File>>,
+        // bytes of file headers not yet read
+        remaining: usize,
+    },
+    // version 0 file hashes come from contents stored after every header,
+    // so the whole blob has to be decoded up front
+    Decoded(std::vec::IntoIter<FileHeaders>),
+}
+
+impl SaveReader {
+    pub fn open(path: &Path) -> Result<SaveReader, Error> {
+        let mut reader = BufReader::new(File::open(path).map_err(Error::Io)?);
+
+        let mut bytes = read_up_to(&mut reader, (MAGIC.len() + U32_LEN) as u64)?;
+        let mut cursor = 0;
+        let version = read_version(&bytes, &mut cursor)?;
+        bytes.drain(..cursor);
+
+        if version == LEGACY_VERSION {
+            reader.read_to_end(&mut bytes).map_err(Error::Io)?;
+            let save = Save::from_bytes(&bytes)?;
+
+            return Ok(SaveReader {
+                headers: save.headers,
+                version,
+                files: FileListing::Decoded(save.blob.headers.into_iter()),
+                index: 0,
+            });
+        }
+
+        // everything up to and including the memo size is fixed-width
+        let fixed_len = HASH_LENGTH + HASH_LENGTH + U64_LEN;
+        let needed = fixed_len.saturating_sub(bytes.len());
+        bytes.extend(read_up_to(&mut reader, needed as u64)?);
+        if bytes.len() == fixed_len {
+            let memo_size = u64::from_be_bytes(bytes[fixed_len - U64_LEN..].try_into().unwrap());
+            let mut rest = memo_size.saturating_add((U128_LEN + CREATOR_LENGTH) as u64);
+            if version >= MERGE_PARENTS_VERSION {
+                rest = rest.saturating_add(U64_LEN as u64);
+            }
+
+            bytes.extend(read_up_to(&mut reader, rest)?);
+
+            // ending with the merged count, and then that many hashes
+            if version >= MERGE_PARENTS_VERSION && (bytes.len() - fixed_len) as u64 == rest {
+                let count_bytes = bytes[bytes.len() - U64_LEN..].try_into().unwrap();
+                let rest = u64::from_be_bytes(count_bytes).saturating_mul(HASH_LENGTH as u64);
+                bytes.extend(read_up_to(&mut reader, rest)?);
+            }
+        }
+
+        let headers = SaveHeaders::from_bytes(&bytes, &mut 0, version)?;
+
+        let mut decoder = Decoder::with_buffer(reader).map_err(Error::Decompression)?;
+        let size_bytes = read_up_to(&mut decoder, U64_LEN as u64)?;
+        let remaining = read_to_length!(&size_bytes, &mut 0, "headers size");
+
+        Ok(SaveReader {
+            headers,
+            version,
+            files: FileListing::Streamed { decoder, remaining },
+            index: 0,
+        })
+    }
+}
+
+fn read_file_header(
+    decoder: &mut Decoder<'static, BufReader<File>>,
+    remaining: &mut usize,
+    version: u32,
+) -> Result<FileHeaders, Error> {
+    let mut bytes = read_up_to(decoder, FILE_HEADER_FIXED_LEN as u64)?;
+    if bytes.len() == FILE_HEADER_FIXED_LEN {
+        let filename_length =
+            u64::from_be_bytes(bytes[FILE_HEADER_FIXED_LEN - U64_LEN..].try_into().unwrap());
+        let mut rest = filename_length.saturating_add(HASH_LENGTH as u64);
+        if version >= FILE_FLAGS_VERSION {
+            rest = rest.saturating_add(1);
+        }
+
+        bytes.extend(read_up_to(decoder, rest)?);
+    }
+
+    let mut cursor = 0;
+    let header = FileHeaders::from_bytes(&bytes, &mut cursor, version)?;
+    *remaining = remaining
+        .checked_sub(cursor)
+        .ok_or(Error::Truncated("file headers"))?;
+
+    Ok(header)
+}
+
+impl Iterator for SaveReader {
+    type Item = Result<FileHeaders, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (decoder, remaining) = match &mut self.files {
+            FileListing::Streamed { decoder, remaining } => (decoder, remaining),
+            FileListing::Decoded(headers) => return headers.next().map(Ok),
+        };
+
+        if *remaining == 0 {
+            return None;
+        }
+
+        let result = read_file_header(decoder, remaining, self.version)
+            .map_err(|e| Error::FileHeader(self.index, Box::new(e)));
+
+        // nothing after a bad header can be trusted
+        if result.is_err() {
+            *remaining = 0;
+        }
+
+        self.index += 1;
+
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(c: char) -> Hash {
+        to_byte_slice!(c.to_string().repeat(HASH_LENGTH).as_bytes(), HASH_LENGTH)
+    }
+
+    fn file(filename: &str, contents: &[u8]) -> FileHeaders {
+        FileHeaders {
+            last_modified: 1_700_000_000_000_000,
+            created: 1_600_000_000_000_000,
+            content_length: contents.len(),
+            filename_length: filename.len(),
+            filename: filename.to_string(),
+            hash: to_byte_slice!(files::get_hash(&contents.to_vec()).as_bytes(), HASH_LENGTH),
+            binary: Some(files::is_binary(contents)),
+        }
+    }
+
+    fn sample(merged: Vec<Hash>) -> Save {
+        let memo = "a memo".to_string();
+        let headers = SaveHeaders {
+            hash: [0; HASH_LENGTH],
+            parent: hash('b'),
+            memo_size: memo.len(),
+            memo,
+            created_date: 1_700_000_000_123_456,
+            creator: to_byte_slice!("recall".as_bytes(), CREATOR_LENGTH),
+            merged,
+        };
+        let blob = Blob {
+            headers: vec![file("./a.txt", b"hello\n"), file("./dir/b.bin", b"\0\x01")],
+        };
+
+        let mut save = Save { headers, blob };
+        save.headers.hash = to_byte_slice!(save.compute_hash().as_bytes(), HASH_LENGTH);
+        save
+    }
+
+    // the bytes an older version of recall would have written
+    fn encode(save: &Save, version: u32) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&version.to_be_bytes());
+
+        let mut headers = save.headers.to_bytes();
+        if version < MERGE_PARENTS_VERSION {
+            // with nothing merged, the count is all there is to drop
+            assert!(save.headers.merged.is_empty());
+            headers.truncate(headers.len() - U64_LEN);
+        }
+        bytes.extend(headers);
+
+        let mut file_headers = Vec::new();
+        for header in save.blob.headers.iter() {
+            let mut header_bytes = header.to_bytes();
+            if version < FILE_FLAGS_VERSION {
+                header_bytes.pop();
+            }
+            file_headers.extend(header_bytes);
+        }
+
+        let mut blob = (file_headers.len() as u64).to_be_bytes().to_vec();
+        blob.extend(file_headers);
+        bytes.extend(encode_all(&blob as &[u8], 3).unwrap());
+
+        bytes
+    }
+
+    // what a listing says about each file
+    fn describe(headers: &[FileHeaders]) -> Vec<(String, usize, String, Option<bool>)> {
+        headers
+            .iter()
+            .map(|h| {
+                let content_hash = h.content_hash();
+                (h.filename.clone(), h.content_length, content_hash, h.binary)
+            })
+            .collect()
+    }
+
+    // a file of its own in the temporary directory, removed when dropped
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, contents: &[u8]) -> TempFile {
+            let path = std::env::temp_dir().join(format!(
+                "recall-storage-{}-{}",
+                std::process::id(),
+                name
+            ));
+            std::fs::write(&path, contents).unwrap();
+
+            TempFile(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn saves_round_trip_through_bytes() {
+        let save = sample(vec![hash('c'), hash('d')]);
+        let read = Save::from_bytes(&save.to_bytes()).unwrap();
+
+        assert_eq!(read.headers.hash, save.headers.hash);
+        assert_eq!(read.headers.parent(), Some("b".repeat(HASH_LENGTH)));
+        assert_eq!(read.headers.memo, "a memo");
+        assert_eq!(read.headers.created_date, save.headers.created_date);
+        assert_eq!(from_byte_slice(&read.headers.creator), "recall");
+        assert_eq!(
+            read.headers.merged(),
+            ["c".repeat(HASH_LENGTH), "d".repeat(HASH_LENGTH)]
+        );
+        assert_eq!(describe(&read.blob.headers), describe(&save.blob.headers));
+        assert_eq!(read.compute_hash(), save.compute_hash());
+        assert_eq!(read.to_bytes(), save.to_bytes());
+    }
+
+    #[test]
+    fn the_reader_agrees_with_the_decoder() {
+        let save = sample(vec![hash('c')]);
+        let file = TempFile::new("reader", &save.to_bytes());
+
+        let reader = SaveReader::open(&file.0).unwrap();
+        assert_eq!(reader.version, FORMAT_VERSION);
+        assert_eq!(reader.headers.hash, save.headers.hash);
+        assert_eq!(reader.headers.merged(), save.headers.merged());
+
+        let headers = reader.collect::<Result<Vec<FileHeaders>, Error>>().unwrap();
+        assert_eq!(describe(&headers), describe(&save.blob.headers));
+    }
+
+    #[test]
+    fn truncated_saves_are_errors() {
+        let bytes = sample(vec![hash('c')]).to_bytes();
+        for length in 0..bytes.len() {
+            if Save::from_bytes(&bytes[..length]).is_ok() {
+                panic!("cut at {}: read anyway", length);
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_file_headers_are_errors() {
+        let blob = sample(Vec::new()).blob.to_bytes();
+        let bytes = zstd::stream::decode_all(&blob as &[u8]).unwrap();
+        for length in 0..bytes.len() {
+            match Blob::headers_from_bytes(&bytes[..length], FORMAT_VERSION) {
+                Err(Error::Truncated(_)) => {}
+                Err(Error::FileHeader(_, e)) if matches!(*e, Error::Truncated(_)) => {}
+                Err(e) => panic!("cut at {}: {}", length, e),
+                Ok(_) => panic!("cut at {}: read anyway", length),
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_saves_are_errors_through_the_reader() {
+        let bytes = sample(vec![hash('c')]).to_bytes();
+        for length in 0..bytes.len() {
+            let file = TempFile::new(&format!("truncated-{}", length), &bytes[..length]);
+            let listed = SaveReader::open(&file.0)
+                .and_then(|reader| reader.collect::<Result<Vec<FileHeaders>, Error>>());
+            if listed.is_ok() {
+                panic!("cut at {}: read anyway", length);
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_baseline_saves_are_errors() {
+        let bytes = std::fs::read(BASELINE_SAVE).unwrap();
+        for length in 0..bytes.len() {
+            if Save::from_legacy_bytes(&bytes[..length]).is_ok() {
+                panic!("cut at {}: read anyway", length);
+            }
+        }
+    }
+
+    #[test]
+    fn save_hashes_ignore_file_order() {
+        let save = sample(Vec::new());
+        let mut reordered = sample(Vec::new());
+        reordered.blob.headers.reverse();
+
+        assert_eq!(reordered.compute_hash(), save.compute_hash());
+    }
+
+    #[test]
+    fn save_hashes_cover_the_memo_and_merges() {
+        let save = sample(Vec::new());
+
+        let mut changed = sample(Vec::new());
+        changed.headers.memo = "another memo".to_string();
+        changed.headers.memo_size = changed.headers.memo.len();
+        assert_ne!(changed.compute_hash(), save.compute_hash());
+
+        let merged = sample(vec![hash('c')]);
+        assert_ne!(merged.compute_hash(), save.compute_hash());
+        assert_ne!(
+            sample(vec![hash('d')]).compute_hash(),
+            merged.compute_hash()
+        );
+    }
+
+    #[test]
+    fn older_versions_decode_with_what_they_recorded() {
+        let save = sample(Vec::new());
+        for version in [MAGIC_VERSION, FILE_FLAGS_VERSION, FORMAT_VERSION] {
+            let bytes = encode(&save, version);
+            assert_eq!(read_version(&bytes, &mut 0).unwrap(), version);
+
+            let read = Save::from_bytes(&bytes).unwrap();
+            assert_eq!(read.headers.memo, "a memo", "version {}", version);
+            assert_eq!(
+                read.compute_hash(),
+                save.compute_hash(),
+                "version {}",
+                version
+            );
+
+            // the binary flag is all version 1 is missing
+            let binary = read.blob.headers.iter().map(|h| h.binary);
+            if version < FILE_FLAGS_VERSION {
+                assert!(binary.clone().all(|b| b.is_none()), "version {}", version);
+            } else {
+                assert_eq!(
+                    binary.collect::<Vec<Option<bool>>>(),
+                    [Some(false), Some(true)]
+                );
+            }
+
+            let file = TempFile::new(&format!("version-{}", version), &bytes);
+            let reader = SaveReader::open(&file.0).unwrap();
+            assert_eq!(reader.version, version);
+            let headers = reader.collect::<Result<Vec<FileHeaders>, Error>>().unwrap();
+            assert_eq!(describe(&headers), describe(&read.blob.headers));
+        }
+    }
+
+    #[test]
+    fn newer_versions_are_refused() {
+        let mut bytes = sample(Vec::new()).to_bytes();
+        bytes[MAGIC.len()..MAGIC.len() + U32_LEN]
+            .copy_from_slice(&(FORMAT_VERSION + 1).to_be_bytes());
+
+        assert!(matches!(
+            Save::from_bytes(&bytes),
+            Err(Error::UnsupportedVersion(_))
+        ));
+    }
+
+    // a save written before the magic was introduced, with the memo "first",
+    // "./a.txt" holding "hello\nworld\n" and "./bin.dat" holding "x\0y"
+    const BASELINE_SAVE: &str =
+        concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/baseline_save");
+
+    #[test]
+    fn baseline_saves_decode_as_version_0() {
+        let bytes = std::fs::read(BASELINE_SAVE).unwrap();
+        assert_eq!(read_version(&bytes, &mut 0).unwrap(), LEGACY_VERSION);
+
+        let (save, contents) = Save::from_legacy_bytes(&bytes).unwrap();
+        assert_eq!(save.headers.memo, "first");
+        assert_eq!(save.headers.parent(), None);
+        assert_eq!(
+            from_byte_slice(&save.headers.hash),
+            "b7994e9163ec508e4ccafdef23903c37ddbf7a74297977521eb1fe4e7174568d"
+        );
+
+        let files = save
+            .blob
+            .headers
+            .iter()
+            .map(|h| (h.filename.as_str(), h.content_length))
+            .collect::<Vec<(&str, usize)>>();
+        assert_eq!(files, [("./a.txt", 12), ("./bin.dat", 3)]);
+        assert_eq!(contents, [b"hello\nworld\n".to_vec(), b"x\0y".to_vec()]);
+
+        for (header, content) in save.blob.headers.iter().zip(contents.iter()) {
+            assert_eq!(header.content_hash(), files::get_hash(content));
+        }
+    }
+
+    #[test]
+    fn baseline_saves_list_through_the_reader() {
+        let reader = SaveReader::open(Path::new(BASELINE_SAVE)).unwrap();
+        assert_eq!(reader.version, LEGACY_VERSION);
+        assert_eq!(reader.headers.memo, "first");
 
-        Save { headers, blob }
+        let filenames = reader.map(|h| h.unwrap().filename).collect::<Vec<String>>();
+        assert_eq!(filenames, ["./a.txt", "./bin.dat"]);
     }
 }